@@ -2,15 +2,22 @@ use qdma_stream::{CardToHostStream, PACKET_SIZE};
 use std::{
     fs,
     io::{self, Read, Seek, Write},
+    os::fd::AsRawFd,
     sync::Arc,
+    time::Duration,
 };
 
 pub trait DataSink {
     fn reset(&mut self) -> io::Result<()>;
 
-    fn read_from_stream<F>(&mut self, stream: &mut CardToHostStream<F>) -> io::Result<usize>
+    /// Reads one message, failing with `TimedOut` if the card sends nothing for `timeout`.
+    fn read_from_stream<F>(
+        &mut self,
+        stream: &mut CardToHostStream<F>,
+        timeout: Duration,
+    ) -> io::Result<usize>
     where
-        F: Read;
+        F: Read + AsRawFd;
 
     fn read_from_stream_raw<F>(
         &mut self,
@@ -30,11 +37,15 @@ where
         Ok(())
     }
 
-    fn read_from_stream<F>(&mut self, stream: &mut CardToHostStream<F>) -> io::Result<usize>
+    fn read_from_stream<F>(
+        &mut self,
+        stream: &mut CardToHostStream<F>,
+        timeout: Duration,
+    ) -> io::Result<usize>
     where
-        F: Read,
+        F: Read + AsRawFd,
     {
-        stream.read_complete_stream(self)
+        stream.read_complete_stream_timeout(self, timeout)
     }
 
     fn read_from_stream_raw<F>(
@@ -78,13 +89,17 @@ impl DataSink for DataSinkCountBytes {
         Ok(())
     }
 
-    fn read_from_stream<F>(&mut self, stream: &mut CardToHostStream<F>) -> io::Result<usize>
+    fn read_from_stream<F>(
+        &mut self,
+        stream: &mut CardToHostStream<F>,
+        timeout: Duration,
+    ) -> io::Result<usize>
     where
-        F: Read,
+        F: Read + AsRawFd,
     {
         let mut bytes = 0;
         loop {
-            let (is_last, packet) = stream.next_stream_packet()?;
+            let (is_last, packet) = stream.next_stream_packet_timeout(timeout)?;
             self.count += packet.len();
             bytes += packet.len();
             if is_last {
@@ -4,6 +4,7 @@ use qdma_stream::{managed, CardToHostStream, HostToCardStream};
 use std::{
     fs,
     io::{Read, Write},
+    os::fd::AsRawFd,
     thread,
 };
 
@@ -65,7 +66,7 @@ impl RunOptions {
     where
         SOURCE: DataSource + Clone + Send + 'static,
         SINK: DataSink + Clone + Send + 'static,
-        C2hFile: Read + Send + 'static,
+        C2hFile: Read + AsRawFd + Send + 'static,
         H2cFile: Write + Send + 'static,
     {
         println!("----- STARTING QUEUES -----");
@@ -4,9 +4,13 @@ use humansize::{ISizeFormatter, BINARY};
 use qdma_stream::{CardToHostStream, HostToCardStream};
 use std::{
     io::{Read, Write},
-    time::Instant,
+    os::fd::AsRawFd,
+    time::{Duration, Instant},
 };
 
+/// Time without data from the card after which reading a message fails instead of hanging.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
 pub fn write_to_queue<F, S>(
     queue: usize,
     mut stream: HostToCardStream<F>,
@@ -31,6 +35,7 @@ where
             bytes += data_source.write_to_stream(&mut stream)?;
         }
     }
+    stream.finish()?;
     let elapsed = start.elapsed().as_secs_f64();
 
     let speed = bytes as f64 / elapsed;
@@ -53,7 +58,7 @@ pub fn read_from_queue<F, S>(
     use_raw: Option<usize>,
 ) -> Result<()>
 where
-    F: Read,
+    F: Read + AsRawFd,
     S: DataSink,
 {
     let start = Instant::now();
@@ -68,7 +73,7 @@ where
         None => {
             for _ in 0..iterations {
                 data_sink.reset()?;
-                bytes += data_sink.read_from_stream(&mut stream)?;
+                bytes += data_sink.read_from_stream(&mut stream, READ_TIMEOUT)?;
             }
         }
     }
@@ -0,0 +1,113 @@
+use crate::{
+    util::{mem_aligned, mem_aligned_free},
+    StreamGeometry,
+};
+use anyhow::{ensure, Result};
+use std::{
+    io::{self, Write},
+    ops::{Deref, DerefMut},
+    ptr::NonNull,
+};
+
+unsafe impl Send for AlignedBuf {}
+unsafe impl Sync for AlignedBuf {}
+
+/// Caller-owned buffer aligned for the queue, which
+/// [`HostToCardStream::write_message_aligned`](crate::HostToCardStream::write_message_aligned)
+/// writes to the device without copying it first.
+///
+/// The buffer has a fixed capacity and a length, like a [`Vec`] that never grows. It dereferences
+/// to its first `len` bytes and [`Write`] appends to it until it is full. The memory is zeroed
+/// when allocated.
+pub struct AlignedBuf {
+    ptr: NonNull<u8>,
+    capacity: usize,
+    align: usize,
+    len: usize,
+}
+
+impl AlignedBuf {
+    /// Allocates an empty buffer of `capacity` bytes, aligned to [`ALIGN`](crate::ALIGN).
+    pub fn new(capacity: usize) -> Result<Self> {
+        Self::with_geometry(capacity, StreamGeometry::default())
+    }
+
+    /// Allocates an empty buffer of `capacity` bytes, aligned for a queue with the given
+    /// geometry.
+    pub fn with_geometry(capacity: usize, geometry: StreamGeometry) -> Result<Self> {
+        ensure!(capacity > 0, "capacity is zero");
+        let align = geometry.align();
+        let ptr = mem_aligned(capacity, align)?;
+        unsafe { ptr.as_ptr().write_bytes(0, capacity) };
+        Ok(Self {
+            ptr,
+            capacity,
+            align,
+            len: 0,
+        })
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn align(&self) -> usize {
+        self.align
+    }
+
+    /// Sets the length of the data. Bytes exposed by growing keep their previous contents.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `len` exceeds the capacity.
+    pub fn set_len(&mut self, len: usize) {
+        assert!(len <= self.capacity, "length exceeds capacity");
+        self.len = len;
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    /// Returns the whole capacity, for filling the buffer before calling
+    /// [`set_len`](Self::set_len).
+    pub fn as_mut_capacity(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.capacity) }
+    }
+}
+
+impl Deref for AlignedBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for AlignedBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Write for AlignedBuf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = usize::min(buf.len(), self.capacity - self.len);
+        let len = self.len;
+        self.as_mut_capacity()[len..len + count].copy_from_slice(&buf[..count]);
+        self.len += count;
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        unsafe {
+            mem_aligned_free(self.ptr.as_ptr(), self.capacity, self.align);
+        }
+    }
+}
@@ -0,0 +1,245 @@
+use super::{
+    buf::ReadBuf, check_poisoned, decoder::Step, next_packet, poisons, C2hDecoder, Resync,
+    BEATS_AHEAD, DEFAULT_READ_CAPACITY,
+};
+use crate::{EscapeMode, Integrity, StreamGeometry, CTRL_SIZE};
+use anyhow::Result;
+use futures::Stream;
+use std::io;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+
+/// Asynchronous counterpart of [`CardToHostStream`](crate::CardToHostStream) for tokio.
+///
+/// Decodes the same framing with the same options, reading from any [`AsyncRead`].
+pub struct AsyncCardToHostStream<F> {
+    file: F,
+    buf: ReadBuf,
+    decoder: C2hDecoder,
+    geometry: StreamGeometry,
+    poisoned: bool,
+}
+
+impl<F> AsyncCardToHostStream<F> {
+    pub fn new(file: F) -> Result<Self> {
+        Self::with_capacity(file, DEFAULT_READ_CAPACITY)
+    }
+
+    /// Creates a stream that reads up to `capacity` bytes from `file` per call. `capacity` must be
+    /// a multiple of [`ALIGN`](crate::ALIGN) and hold at least three beats.
+    pub fn with_capacity(file: F, capacity: usize) -> Result<Self> {
+        Self::with_geometry(file, capacity, StreamGeometry::default())
+    }
+
+    /// Like [`with_capacity`](Self::with_capacity), but for a queue with the given packet size
+    /// and alignment.
+    pub fn with_geometry(file: F, capacity: usize, geometry: StreamGeometry) -> Result<Self> {
+        anyhow::ensure!(
+            capacity >= BEATS_AHEAD * (geometry.packet_size() + CTRL_SIZE),
+            "capacity too small"
+        );
+
+        Ok(Self {
+            file,
+            buf: ReadBuf::new(capacity, geometry.align())?,
+            decoder: C2hDecoder::new(geometry),
+            geometry,
+            poisoned: false,
+        })
+    }
+
+    pub fn geometry(&self) -> StreamGeometry {
+        self.geometry
+    }
+
+    pub fn escape_mode(&self) -> EscapeMode {
+        self.decoder.escape_mode()
+    }
+
+    /// See [`CardToHostStream::set_escape_mode`](crate::CardToHostStream::set_escape_mode).
+    pub fn set_escape_mode(&mut self, mode: EscapeMode) {
+        self.decoder.set_escape_mode(mode);
+    }
+
+    pub fn integrity(&self) -> Integrity {
+        self.decoder.integrity()
+    }
+
+    /// See [`CardToHostStream::set_integrity`](crate::CardToHostStream::set_integrity).
+    pub fn set_integrity(&mut self, integrity: Integrity) {
+        self.decoder.set_integrity(integrity);
+    }
+
+    pub fn max_message_size(&self) -> Option<usize> {
+        self.decoder.max_message_size()
+    }
+
+    /// See [`CardToHostStream::set_max_message_size`](crate::CardToHostStream::set_max_message_size).
+    pub fn set_max_message_size(&mut self, max: Option<usize>) {
+        self.decoder.set_max_message_size(max);
+    }
+
+    pub fn sequence_numbers(&self) -> bool {
+        self.decoder.sequence_numbers()
+    }
+
+    /// See [`CardToHostStream::set_sequence_numbers`](crate::CardToHostStream::set_sequence_numbers).
+    pub fn set_sequence_numbers(&mut self, enabled: bool) {
+        self.decoder.set_sequence_numbers(enabled);
+    }
+
+    /// Sequence number of the last message whose first packet was read.
+    pub fn last_sequence(&self) -> Option<u32> {
+        self.decoder.last_sequence()
+    }
+
+    /// See [`CardToHostStream::is_poisoned`](crate::CardToHostStream::is_poisoned).
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned
+    }
+
+    /// Discards all buffered data and returns the decoder to the start of a message.
+    pub fn reset(&mut self) {
+        let len = self.buf.data().len();
+        self.buf.consume(len);
+        self.decoder.skip(len);
+        self.decoder.reset();
+        self.poisoned = false;
+    }
+}
+
+impl<F> AsyncCardToHostStream<F>
+where
+    F: AsyncRead + Unpin,
+{
+    /// Returns `(is_last, data)`
+    pub async fn next_stream_packet(&mut self) -> io::Result<(bool, &[u8])> {
+        check_poisoned(&self.decoder, self.poisoned)?;
+        loop {
+            match next_packet(&mut self.decoder, self.buf.data())? {
+                Step::NeedMore(len) => {
+                    if let Err(err) = self.buf.fill_from_async(&mut self.file, len).await {
+                        self.poisoned = poisons(&self.decoder, &err);
+                        return Err(err);
+                    }
+                }
+                Step::Packet {
+                    is_last,
+                    data,
+                    consumed,
+                } => return Ok((is_last, self.buf.take(consumed, data))),
+            }
+        }
+    }
+
+    pub async fn read_complete_stream(
+        &mut self,
+        mut buf: impl AsyncWrite + Unpin,
+    ) -> io::Result<usize> {
+        let mut bytes = 0;
+        loop {
+            let (is_last, packet) = self.next_stream_packet().await?;
+            buf.write_all(packet).await?;
+            bytes += packet.len();
+            if is_last {
+                break Ok(bytes);
+            }
+        }
+    }
+
+    /// See [`CardToHostStream::resync`](crate::CardToHostStream::resync).
+    pub async fn resync(&mut self) -> io::Result<u64> {
+        let mut discarded = 0;
+        loop {
+            let (consumed, resync) = self.decoder.resync(self.buf.data());
+            self.buf.consume(consumed);
+            discarded += consumed as u64;
+            match resync {
+                Resync::Found => {
+                    self.poisoned = false;
+                    break Ok(discarded);
+                }
+                Resync::NeedMore(len) => self.buf.fill_from_async(&mut self.file, len).await?,
+            }
+        }
+    }
+
+    /// Returns a [`Stream`] of whole messages.
+    ///
+    /// The stream ends when the file ends at a message boundary, or after the first error. The
+    /// error leaves this stream usable, e.g. for [`resync`](Self::resync). Pin it, e.g. with
+    /// [`pin!`](std::pin::pin), to use methods like `StreamExt::next`.
+    pub fn messages(&mut self) -> impl Stream<Item = io::Result<Vec<u8>>> + '_ {
+        futures::stream::unfold(Some(self), |this| async move {
+            let this = this?;
+            let mut message = Vec::new();
+            match this.read_complete_stream(&mut message).await {
+                Ok(_) => Some((Ok(message), Some(this))),
+                Err(err)
+                    if err.kind() == io::ErrorKind::UnexpectedEof
+                        && message.is_empty()
+                        && this.buf.data().is_empty() =>
+                {
+                    None
+                }
+                Err(err) => Some((Err(err), None)),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::messages, C2hEncoder};
+    use futures::StreamExt;
+
+    /// Writes the C2H beats of `messages` to a duplex pipe from another task, returns the reading
+    /// end.
+    fn card(
+        geometry: StreamGeometry,
+        integrity: Integrity,
+        messages: Vec<Vec<u8>>,
+    ) -> tokio::io::DuplexStream {
+        let (mut card, host) = tokio::io::duplex(100);
+        let mut encoder = C2hEncoder::new(geometry);
+        encoder.set_integrity(integrity);
+        tokio::spawn(async move {
+            for message in messages {
+                let mut beats = Vec::new();
+                encoder.encode(&message, &mut beats).unwrap();
+                card.write_all(&beats).await.unwrap();
+            }
+        });
+        host
+    }
+
+    #[tokio::test]
+    async fn read_complete_stream_through_duplex() {
+        let geometry = StreamGeometry::new(64, 64).unwrap();
+        let sent = messages(geometry.packet_size());
+        let file = card(geometry, Integrity::Crc32c, sent.clone());
+        let mut stream = AsyncCardToHostStream::with_geometry(file, 1024, geometry).unwrap();
+        stream.set_integrity(Integrity::Crc32c);
+
+        for message in sent {
+            let mut received = Vec::new();
+            stream.read_complete_stream(&mut received).await.unwrap();
+            assert_eq!(received, message);
+        }
+    }
+
+    #[tokio::test]
+    async fn messages_stream_through_duplex() {
+        let geometry = StreamGeometry::new(64, 64).unwrap();
+        let sent = messages(geometry.packet_size());
+        let file = card(geometry, Integrity::None, sent.clone());
+        let mut stream = AsyncCardToHostStream::with_geometry(file, 1024, geometry).unwrap();
+
+        let received = stream
+            .messages()
+            .map(Result::unwrap)
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(received, sent);
+    }
+}
@@ -0,0 +1,147 @@
+use crate::util::{mem_aligned, mem_aligned_free};
+use anyhow::{ensure, Result};
+use std::{
+    io::{self, Read},
+    ops::Range,
+    ptr::{self, NonNull},
+};
+
+unsafe impl Send for ReadBuf {}
+unsafe impl Sync for ReadBuf {}
+
+/// Aligned read buffer holding the not yet decoded part of the C2H stream.
+///
+/// The allocation has one extra alignment block of headroom, so the unconsumed tail can always be
+/// moved in front of an aligned boundary and the next read targets an aligned region.
+pub struct ReadBuf {
+    ptr: NonNull<u8>,
+    size: usize,
+    align: usize,
+    start: usize,
+    end: usize,
+}
+
+impl ReadBuf {
+    pub fn new(capacity: usize, align: usize) -> Result<Self> {
+        ensure!(capacity % align == 0);
+        let size = capacity + align;
+        let ptr = mem_aligned(size, align)?;
+        Ok(Self {
+            ptr,
+            size,
+            align,
+            start: 0,
+            end: 0,
+        })
+    }
+
+    pub fn data(&self) -> &[u8] {
+        unsafe {
+            std::slice::from_raw_parts(self.ptr.as_ptr().add(self.start), self.end - self.start)
+        }
+    }
+
+    pub fn consume(&mut self, count: usize) {
+        debug_assert!(count <= self.end - self.start);
+        self.start += count;
+    }
+
+    /// Consumes `count` bytes and returns `range` relative to the consumed region.
+    pub fn take(&mut self, count: usize, range: Range<usize>) -> &[u8] {
+        debug_assert!(count <= self.end - self.start && range.end <= count);
+        let start = self.start;
+        self.start += count;
+        unsafe {
+            std::slice::from_raw_parts(self.ptr.as_ptr().add(start + range.start), range.len())
+        }
+    }
+
+    /// Reads from `reader` until at least `len` bytes are buffered.
+    pub fn fill_from<R: Read>(&mut self, mut reader: R, len: usize) -> io::Result<()> {
+        self.prepare(len)?;
+        while self.end - self.start < len {
+            match reader.read(self.spare_mut()) {
+                Ok(0) => return Err(eof()),
+                Ok(count) => self.end += count,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Like [`fill_from`](Self::fill_from), for an asynchronous reader.
+    #[cfg(feature = "tokio")]
+    pub async fn fill_from_async<R>(&mut self, reader: &mut R, len: usize) -> io::Result<()>
+    where
+        R: tokio::io::AsyncRead + Unpin,
+    {
+        use tokio::io::AsyncReadExt;
+
+        self.prepare(len)?;
+        while self.end - self.start < len {
+            match reader.read(self.spare_mut()).await {
+                Ok(0) => return Err(eof()),
+                Ok(count) => self.end += count,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Makes room for reading until `len` bytes are buffered. Fails if `len` exceeds the
+    /// capacity.
+    fn prepare(&mut self, len: usize) -> io::Result<()> {
+        if self.end - self.start >= len {
+            return Ok(());
+        }
+        let capacity = self.size - self.align;
+        if len > capacity {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} bytes do not fit in a read buffer of {} bytes",
+                    len, capacity
+                ),
+            ));
+        }
+
+        // Move the remaining bytes in front of an aligned boundary
+        let remaining = self.end - self.start;
+        let start = (self.align - remaining % self.align) % self.align;
+        if start != self.start {
+            unsafe {
+                ptr::copy(
+                    self.ptr.as_ptr().add(self.start),
+                    self.ptr.as_ptr().add(start),
+                    remaining,
+                );
+            }
+            self.start = start;
+            self.end = start + remaining;
+        }
+
+        Ok(())
+    }
+
+    fn spare_mut(&mut self) -> &mut [u8] {
+        unsafe {
+            std::slice::from_raw_parts_mut(self.ptr.as_ptr().add(self.end), self.size - self.end)
+        }
+    }
+}
+
+fn eof() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "failed to fill whole buffer")
+}
+
+impl Drop for ReadBuf {
+    fn drop(&mut self) {
+        unsafe {
+            mem_aligned_free(self.ptr.as_ptr(), self.size, self.align);
+        }
+    }
+}
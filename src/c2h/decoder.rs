@@ -0,0 +1,876 @@
+use super::{C2hError, ProtocolState};
+use crate::{framing::SEQUENCE_LEN, EscapeMode, Integrity, StreamGeometry, CTRL_SEQ, CTRL_SIZE};
+use std::ops::Range;
+
+/// Decoder of the C2H framing that works on byte slices and does no I/O itself.
+///
+/// [`decode`](Self::decode) is given the not yet consumed part of the stream and reports how many
+/// bytes it consumed together with the next [`C2hEvent`]. When it asks for more input, the caller
+/// appends to the unconsumed bytes and calls it again, so the input always starts where the
+/// consumed part ended. [`CardToHostStream`](super::CardToHostStream) is a buffer and a file around
+/// this decoder.
+///
+/// The decoder never consumes a data packet before the beat following it is known, because only
+/// that beat tells whether the packet ends the message. With a trailer, the beat after that is
+/// needed as well, because a short last packet moves part of the trailer into its predecessor.
+#[derive(Debug)]
+pub struct C2hDecoder {
+    packet_size: usize,
+    escape_mode: EscapeMode,
+    integrity: Integrity,
+    max_message_size: Option<usize>,
+    protocol_state: ProtocolState,
+    offset: u64,
+    message: u64,
+    message_len: usize,
+    crc: u32,
+    trailer: [u8; 4],
+    trailer_len: usize,
+    sequence_numbers: bool,
+    last_sequence: Option<u32>,
+    expected_sequence: Option<u32>,
+    /// Length of a message whose last data has been returned, but not its end.
+    pending_end: Option<usize>,
+}
+
+/// Event reported by [`C2hDecoder::decode`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum C2hEvent<'a> {
+    /// Data of the current message, possibly empty.
+    Data(&'a [u8]),
+    /// The current message ended after `len` bytes of data.
+    MessageEnd { len: usize },
+    /// The input holds less than a beat. Call again with at least this many bytes.
+    NeedMore(usize),
+    /// The input cannot be decoded. Nothing is consumed, the same error is reported again unless
+    /// the decoder is [resynchronized](C2hDecoder::resync) or [reset](C2hDecoder::reset).
+    ///
+    /// [`C2hError::SequenceMismatch`] is the exception: the check continues from the sequence
+    /// number of the message, so decoding the same input again accepts the message.
+    Error(C2hError),
+}
+
+/// Result of [`C2hDecoder::resync`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resync {
+    /// The consumed bytes end with a control beat that ends a message.
+    Found,
+    /// No message end was found. Call again with at least this many bytes.
+    NeedMore(usize),
+}
+
+#[derive(Debug)]
+pub(crate) enum Step {
+    /// At least this many bytes are needed to make progress.
+    NeedMore(usize),
+    /// The next packet is `input[data]`, `consumed` bytes of the input are used up.
+    Packet {
+        is_last: bool,
+        data: Range<usize>,
+        consumed: usize,
+    },
+}
+
+impl C2hDecoder {
+    pub fn new(geometry: StreamGeometry) -> Self {
+        Self {
+            packet_size: geometry.packet_size(),
+            escape_mode: EscapeMode::Disabled,
+            integrity: Integrity::None,
+            max_message_size: None,
+            protocol_state: ProtocolState::NotSet,
+            offset: 0,
+            message: 0,
+            message_len: 0,
+            crc: 0,
+            trailer: [0; 4],
+            trailer_len: 0,
+            sequence_numbers: false,
+            last_sequence: None,
+            expected_sequence: None,
+            pending_end: None,
+        }
+    }
+
+    pub fn escape_mode(&self) -> EscapeMode {
+        self.escape_mode
+    }
+
+    /// See [`CardToHostStream::set_escape_mode`](super::CardToHostStream::set_escape_mode).
+    pub fn set_escape_mode(&mut self, mode: EscapeMode) {
+        self.escape_mode = mode;
+    }
+
+    pub fn integrity(&self) -> Integrity {
+        self.integrity
+    }
+
+    /// See [`CardToHostStream::set_integrity`](super::CardToHostStream::set_integrity).
+    pub fn set_integrity(&mut self, integrity: Integrity) {
+        self.integrity = integrity;
+    }
+
+    pub fn max_message_size(&self) -> Option<usize> {
+        self.max_message_size
+    }
+
+    /// See [`CardToHostStream::set_max_message_size`](super::CardToHostStream::set_max_message_size).
+    pub fn set_max_message_size(&mut self, max: Option<usize>) {
+        self.max_message_size = max;
+    }
+
+    pub fn sequence_numbers(&self) -> bool {
+        self.sequence_numbers
+    }
+
+    /// See [`CardToHostStream::set_sequence_numbers`](super::CardToHostStream::set_sequence_numbers).
+    pub fn set_sequence_numbers(&mut self, enabled: bool) {
+        self.sequence_numbers = enabled;
+    }
+
+    /// Sequence number of the last message whose first packet was decoded.
+    pub fn last_sequence(&self) -> Option<u32> {
+        self.last_sequence
+    }
+
+    pub fn state(&self) -> ProtocolState {
+        self.protocol_state
+    }
+
+    /// Stream offset of the next byte to decode.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Index of the message being decoded.
+    pub(crate) fn message(&self) -> u64 {
+        self.message
+    }
+
+    /// Accounts for bytes consumed outside of the framing.
+    pub(crate) fn skip(&mut self, count: usize) {
+        self.offset += count as u64;
+    }
+
+    /// Decodes the next event from the start of `input`, returns the number of bytes consumed and
+    /// the event.
+    ///
+    /// The data of the last packet of a message is followed by [`C2hEvent::MessageEnd`], which
+    /// consumes nothing. A last packet without data, e.g. one holding only the trailer, is
+    /// consumed by the [`C2hEvent::MessageEnd`] itself.
+    pub fn decode<'a>(&mut self, input: &'a [u8]) -> (usize, C2hEvent<'a>) {
+        if let Some(len) = self.pending_end.take() {
+            return (0, C2hEvent::MessageEnd { len });
+        }
+
+        match self.step(input) {
+            Ok(Step::NeedMore(len)) => (0, C2hEvent::NeedMore(len)),
+            Ok(Step::Packet {
+                is_last,
+                data,
+                consumed,
+            }) => {
+                if is_last && data.is_empty() {
+                    let len = self.pending_end.take().unwrap();
+                    (consumed, C2hEvent::MessageEnd { len })
+                } else {
+                    (consumed, C2hEvent::Data(&input[data]))
+                }
+            }
+            Err(err) => (0, C2hEvent::Error(err)),
+        }
+    }
+
+    /// Decodes the next packet, the building block of [`decode`](Self::decode).
+    fn step(&mut self, input: &[u8]) -> Result<Step, C2hError> {
+        let validate = self.escape_mode != EscapeMode::Disabled;
+
+        let (meta, len) = match parse_beat(input, self.packet_size, validate) {
+            Ok(beat) => beat,
+            Err(required) => return Ok(Step::NeedMore(required)),
+        };
+        match meta {
+            BeatMeta::ThisIsData => (),
+            BeatMeta::ThisIsLast(data_len) => return self.last_packet(input, data_len, len),
+            BeatMeta::PrevIsLast(_, ctrl) => {
+                return Err(C2hError::UnexpectedPrevIsLast {
+                    ctrl,
+                    state: self.protocol_state,
+                    offset: self.offset,
+                    message: self.message,
+                })
+            }
+            BeatMeta::Invalid(ctrl) => return Err(self.invalid_ctrl(ctrl, 0)),
+        }
+
+        let (next_meta, next_len) = match parse_beat(&input[len..], self.packet_size, validate) {
+            Ok(beat) => beat,
+            Err(required) => return Ok(Step::NeedMore(len + required)),
+        };
+        match next_meta {
+            BeatMeta::ThisIsData if self.integrity != Integrity::None => {
+                // The beat after the next one tells whether the next packet is the last one
+                let after_next = &input[len + next_len..];
+                match parse_beat(after_next, self.packet_size, validate) {
+                    Ok((BeatMeta::PrevIsLast(data_len, _), _)) => {
+                        self.data_packet(input, data_len, len)
+                    }
+                    Ok((BeatMeta::Invalid(ctrl), _)) => {
+                        Err(self.invalid_ctrl(ctrl, len + next_len))
+                    }
+                    Ok(_) => self.data_packet(input, self.packet_size, len),
+                    Err(required) => Ok(Step::NeedMore(len + next_len + required)),
+                }
+            }
+            BeatMeta::ThisIsData => self.data_packet(input, self.packet_size, len),
+            BeatMeta::ThisIsLast(data_len) => self.data_packet(input, data_len, len),
+            BeatMeta::PrevIsLast(data_len, _) => self.last_packet(input, data_len, len + next_len),
+            BeatMeta::Invalid(ctrl) => Err(self.invalid_ctrl(ctrl, len)),
+        }
+    }
+
+    /// Searches `input` for the next control beat that ends a message, so that decoding can
+    /// continue after an error. Returns the number of bytes to discard and whether a message end
+    /// was found.
+    ///
+    /// Control words are validated regardless of the [`EscapeMode`].
+    pub fn resync(&mut self, input: &[u8]) -> (usize, Resync) {
+        self.pending_end = None;
+
+        let mut start = 0;
+        while let Some(pos) = input[start..]
+            .windows(CTRL_SEQ.len())
+            .position(|window| window == CTRL_SEQ)
+        {
+            let pos = start + pos;
+            match parse_beat(&input[pos..], self.packet_size, true) {
+                Ok((BeatMeta::ThisIsLast(_) | BeatMeta::PrevIsLast(..), len)) => {
+                    self.offset += (pos + len) as u64;
+                    self.protocol_state = ProtocolState::NotSet;
+                    self.message += 1;
+                    self.clear_message();
+                    return (pos + len, Resync::Found);
+                }
+                Ok((BeatMeta::ThisIsData | BeatMeta::Invalid(_), _)) => start = pos + 1,
+                Err(required) => {
+                    self.offset += pos as u64;
+                    return (pos, Resync::NeedMore(required));
+                }
+            }
+        }
+
+        // Keep a possible prefix of the control sequence
+        let consumed = input.len().saturating_sub(CTRL_SEQ.len() - 1);
+        self.offset += consumed as u64;
+        (consumed, Resync::NeedMore(input.len() - consumed + 1))
+    }
+
+    /// Returns to the start of a message, for input that starts a new message.
+    pub fn reset(&mut self) {
+        self.pending_end = None;
+        self.protocol_state = ProtocolState::NotSet;
+        self.clear_message();
+    }
+
+    fn clear_message(&mut self) {
+        self.message_len = 0;
+        self.crc = 0;
+        self.trailer_len = 0;
+    }
+
+    fn invalid_ctrl(&self, ctrl: u32, position: usize) -> C2hError {
+        C2hError::InvalidCtrl {
+            ctrl,
+            state: self.protocol_state,
+            offset: self.offset + position as u64,
+            message: self.message,
+        }
+    }
+
+    /// Returns a data packet that is not the last one of its message, `next_len` is the length
+    /// of the packet following it.
+    fn data_packet(
+        &mut self,
+        input: &[u8],
+        next_len: usize,
+        consumed: usize,
+    ) -> Result<Step, C2hError> {
+        // Hold back the part of the trailer that does not fit into the next packet
+        let held_back = self.integrity.trailer_len().saturating_sub(next_len);
+        let data_len = self.packet_size - held_back;
+        let (header_len, sequence) = self.check_sequence(&input[..data_len])?;
+        self.check_limit(data_len - header_len)?;
+
+        self.trailer[..held_back].copy_from_slice(&input[data_len..self.packet_size]);
+        self.trailer_len = held_back;
+        self.update_crc(&input[..data_len]);
+
+        self.offset += consumed as u64;
+        self.protocol_state = ProtocolState::Data;
+        self.message_len += data_len - header_len;
+        self.commit_sequence(sequence);
+
+        Ok(Step::Packet {
+            is_last: false,
+            data: header_len..data_len,
+            consumed,
+        })
+    }
+
+    /// Returns the last packet of a message, `packet_len` includes the trailer.
+    fn last_packet(
+        &mut self,
+        input: &[u8],
+        packet_len: usize,
+        consumed: usize,
+    ) -> Result<Step, C2hError> {
+        let trailer_len = self.integrity.trailer_len();
+        let data_len = packet_len.saturating_sub(trailer_len);
+        let (header_len, sequence) = self.check_sequence(&input[..data_len])?;
+        self.check_limit(data_len - header_len)?;
+
+        if self.integrity == Integrity::Crc32c {
+            let missing = trailer_len - (packet_len - data_len);
+            if self.trailer_len != missing {
+                return Err(C2hError::MissingTrailer {
+                    offset: self.offset,
+                    message: self.message,
+                });
+            }
+            self.trailer[missing..].copy_from_slice(&input[data_len..packet_len]);
+
+            let expected = u32::from_le_bytes(self.trailer);
+            let actual = crc32c::crc32c_append(self.crc, &input[..data_len]);
+            if expected != actual {
+                return Err(C2hError::CrcMismatch {
+                    expected,
+                    actual,
+                    offset: self.offset,
+                    message: self.message,
+                });
+            }
+        }
+
+        self.offset += consumed as u64;
+        self.protocol_state = ProtocolState::NotSet;
+        self.message += 1;
+        self.pending_end = Some(self.message_len + data_len - header_len);
+        self.clear_message();
+        self.commit_sequence(sequence);
+
+        Ok(Step::Packet {
+            is_last: true,
+            data: header_len..data_len,
+            consumed,
+        })
+    }
+
+    /// Checks the sequence number at the start of the first packet of a message, returns the
+    /// length of the header and the sequence number.
+    ///
+    /// On a mismatch, the received number becomes the expected one, so that the packet is
+    /// accepted when decoded again.
+    fn check_sequence(&mut self, data: &[u8]) -> Result<(usize, Option<u32>), C2hError> {
+        if !self.sequence_numbers || self.protocol_state != ProtocolState::NotSet {
+            return Ok((0, None));
+        }
+        let Some(header) = data.first_chunk::<SEQUENCE_LEN>() else {
+            return Err(C2hError::MissingHeader {
+                offset: self.offset,
+                message: self.message,
+            });
+        };
+
+        let found = u32::from_le_bytes(*header);
+        match self.expected_sequence {
+            Some(expected) if expected != found => {
+                self.expected_sequence = Some(found);
+                Err(C2hError::SequenceMismatch {
+                    expected,
+                    found,
+                    offset: self.offset,
+                    message: self.message,
+                })
+            }
+            _ => Ok((SEQUENCE_LEN, Some(found))),
+        }
+    }
+
+    fn commit_sequence(&mut self, sequence: Option<u32>) {
+        if let Some(sequence) = sequence {
+            self.last_sequence = Some(sequence);
+            self.expected_sequence = Some(sequence.wrapping_add(1));
+        }
+    }
+
+    fn check_limit(&self, data_len: usize) -> Result<(), C2hError> {
+        match self.max_message_size {
+            Some(limit) if self.message_len + data_len > limit => Err(C2hError::MessageTooLarge {
+                limit,
+                offset: self.offset,
+                message: self.message,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    fn update_crc(&mut self, data: &[u8]) {
+        if self.integrity == Integrity::Crc32c {
+            self.crc = crc32c::crc32c_append(self.crc, data);
+        }
+    }
+}
+
+/// Parses the beat at the start of `input`, returns its meta and length including the control
+/// word or the number of bytes required to do so.
+///
+/// Without validation, lengths are clamped to the packet size.
+fn parse_beat(
+    input: &[u8],
+    packet_size: usize,
+    validate: bool,
+) -> Result<(BeatMeta, usize), usize> {
+    if input.len() < packet_size {
+        return Err(packet_size);
+    }
+    if !input.starts_with(&CTRL_SEQ) {
+        return Ok((BeatMeta::ThisIsData, packet_size));
+    }
+    if input.len() < packet_size + CTRL_SIZE {
+        return Err(packet_size + CTRL_SIZE);
+    }
+
+    let ctrl = &input[packet_size..packet_size + CTRL_SIZE];
+    let ctrl = u32::from_le_bytes([ctrl[0], ctrl[1], ctrl[2], ctrl[3]]);
+    let len = (ctrl & !(1 << 31)) as usize;
+    let meta = if ctrl == 0 {
+        BeatMeta::ThisIsData
+    } else if validate && (len == 0 || len > packet_size) {
+        BeatMeta::Invalid(ctrl)
+    } else if validate && (ctrl & (1 << 31)) == 0 && len < CTRL_SEQ.len() {
+        // The data of a ThisIsLast beat includes the control sequence
+        BeatMeta::Invalid(ctrl)
+    } else if (ctrl & (1 << 31)) == 0 {
+        BeatMeta::ThisIsLast(usize::min(packet_size, len))
+    } else {
+        BeatMeta::PrevIsLast(usize::min(packet_size, len), ctrl)
+    };
+
+    Ok((meta, packet_size + CTRL_SIZE))
+}
+
+#[derive(Debug, Clone, Copy)]
+enum BeatMeta {
+    ThisIsData,
+    ThisIsLast(usize),
+    PrevIsLast(usize, u32),
+    Invalid(u32),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::message, C2hEncoder, LastEncoding};
+
+    const PACKET_SIZE: usize = 64;
+
+    fn geometry() -> StreamGeometry {
+        StreamGeometry::new(PACKET_SIZE, 64).unwrap()
+    }
+
+    fn decoder(escape: bool, integrity: Integrity, sequence_numbers: bool) -> C2hDecoder {
+        let mut decoder = C2hDecoder::new(geometry());
+        if escape {
+            decoder.set_escape_mode(EscapeMode::Escaped);
+        }
+        decoder.set_integrity(integrity);
+        decoder.set_sequence_numbers(sequence_numbers);
+        decoder
+    }
+
+    fn encoder(escape: bool, integrity: Integrity, last_encoding: LastEncoding) -> C2hEncoder {
+        let mut encoder = C2hEncoder::new(geometry());
+        encoder.set_escape(escape);
+        encoder.set_integrity(integrity);
+        encoder.set_last_encoding(last_encoding);
+        encoder
+    }
+
+    /// Returns messages of the given lengths whose packets start with [`CTRL_SEQ`] wherever there
+    /// is room, or only their last packet without escaping, so that escaped and ThisIsLast beats
+    /// are produced.
+    fn messages(lengths: &[usize], encoder: &C2hEncoder, sequence_numbers: bool) -> Vec<Vec<u8>> {
+        let header_len = if sequence_numbers { SEQUENCE_LEN } else { 0 };
+        let trailer_len = encoder.integrity().trailer_len();
+        lengths
+            .iter()
+            .map(|&len| {
+                let mut data = message(len);
+                let last = (header_len + len + trailer_len - 1) / PACKET_SIZE * PACKET_SIZE;
+                let first = if encoder.escape() { 0 } else { last };
+                for start in (first..=last).step_by(PACKET_SIZE) {
+                    if start >= header_len && start + CTRL_SEQ.len() <= header_len + len {
+                        let start = start - header_len;
+                        data[start..start + CTRL_SEQ.len()].copy_from_slice(&CTRL_SEQ);
+                    }
+                }
+                data
+            })
+            .collect()
+    }
+
+    /// Encodes `messages`, each preceded by its index as sequence number if enabled.
+    fn encode(encoder: &C2hEncoder, messages: &[Vec<u8>], sequence_numbers: bool) -> Vec<u8> {
+        let mut beats = Vec::new();
+        for (index, message) in messages.iter().enumerate() {
+            let mut framed = Vec::new();
+            if sequence_numbers {
+                framed.extend_from_slice(&u32::to_le_bytes(index as u32));
+            }
+            framed.extend_from_slice(message);
+            encoder.encode(&framed, &mut beats).unwrap();
+        }
+        beats
+    }
+
+    /// Result of [`decode_split`].
+    #[derive(Debug)]
+    struct Decoded {
+        messages: Vec<Vec<u8>>,
+        /// Data of an unfinished message.
+        partial: Vec<u8>,
+        consumed: usize,
+        error: Option<C2hError>,
+    }
+
+    /// Decodes `input`, of which the first `split` bytes are available first and the rest once
+    /// the decoder asks for more. Stops at the end of the input or at the first error.
+    fn decode_split(decoder: &mut C2hDecoder, input: &[u8], split: usize) -> Decoded {
+        let start = decoder.offset();
+        let mut decoded = Decoded {
+            messages: Vec::new(),
+            partial: Vec::new(),
+            consumed: 0,
+            error: None,
+        };
+        let mut end = split;
+        loop {
+            assert_eq!(decoder.offset() - start, decoded.consumed as u64);
+            let (consumed, event) = decoder.decode(&input[decoded.consumed..end]);
+            decoded.consumed += consumed;
+            match event {
+                C2hEvent::Data(data) => decoded.partial.extend_from_slice(data),
+                C2hEvent::MessageEnd { len } => {
+                    assert_eq!(len, decoded.partial.len());
+                    decoded.messages.push(std::mem::take(&mut decoded.partial));
+                }
+                C2hEvent::NeedMore(required) => {
+                    assert_eq!(consumed, 0);
+                    assert!(required > end - decoded.consumed);
+                    if end == input.len() {
+                        break decoded;
+                    }
+                    end = input.len();
+                }
+                C2hEvent::Error(err) => {
+                    assert_eq!(consumed, 0);
+                    // Nothing is consumed, the error is reported again, except for a sequence
+                    // mismatch, after which the message is accepted
+                    if !matches!(err, C2hError::SequenceMismatch { .. }) {
+                        let (consumed, again) = decoder.decode(&input[decoded.consumed..end]);
+                        assert_eq!((consumed, again), (0, C2hEvent::Error(err.clone())));
+                    }
+                    decoded.error = Some(err);
+                    break decoded;
+                }
+            }
+        }
+    }
+
+    /// Decodes `input` split at every position with decoders made by `decoder`, checks that all
+    /// splits agree and returns the result.
+    fn decode_every_split(decoder: impl Fn() -> C2hDecoder, input: &[u8]) -> Decoded {
+        let reference = decode_split(&mut decoder(), input, input.len());
+        for split in 0..input.len() {
+            let decoded = decode_split(&mut decoder(), input, split);
+            assert_eq!(decoded.messages, reference.messages, "split at {split}");
+            assert_eq!(decoded.partial, reference.partial, "split at {split}");
+            assert_eq!(decoded.consumed, reference.consumed, "split at {split}");
+            assert_eq!(decoded.error, reference.error, "split at {split}");
+        }
+        reference
+    }
+
+    /// A control beat carrying `ctrl`.
+    fn ctrl_beat(ctrl: u32) -> Vec<u8> {
+        let mut beat = CTRL_SEQ.to_vec();
+        beat.resize(PACKET_SIZE, 0);
+        beat.extend_from_slice(&u32::to_le_bytes(ctrl));
+        beat
+    }
+
+    #[test]
+    fn round_trip_split_everywhere() {
+        let p = PACKET_SIZE;
+        let lengths = [1, 4, 5, p - 1, p, p + 1, p + 4, 2 * p, 3 * p + 5];
+        for escape in [false, true] {
+            for integrity in [Integrity::None, Integrity::Crc32c] {
+                for last_encoding in [LastEncoding::ThisIsLast, LastEncoding::PrevIsLast] {
+                    for sequence_numbers in [false, true] {
+                        let encoder = encoder(escape, integrity, last_encoding);
+                        let messages = messages(&lengths, &encoder, sequence_numbers);
+                        let input = encode(&encoder, &messages, sequence_numbers);
+
+                        let make = || decoder(escape, integrity, sequence_numbers);
+                        let decoded = decode_every_split(make, &input);
+                        assert_eq!(decoded.error, None);
+                        assert_eq!(decoded.consumed, input.len());
+                        assert!(decoded.partial.is_empty());
+                        assert_eq!(decoded.messages, messages);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn whole_packets_split_everywhere() {
+        for integrity in [Integrity::None, Integrity::Crc32c] {
+            let mut encoder = encoder(true, integrity, LastEncoding::ThisIsLast);
+            encoder.set_whole_packets(true);
+            let messages = messages(&[1, 60, 64, 100], &encoder, false);
+            let input = encode(&encoder, &messages, false);
+
+            let decoded = decode_every_split(|| decoder(true, integrity, false), &input);
+            assert_eq!(decoded.error, None);
+            for (decoded, sent) in decoded.messages.iter().zip(&messages) {
+                let len = (sent.len() + integrity.trailer_len()).next_multiple_of(PACKET_SIZE);
+                assert_eq!(decoded.len(), len - integrity.trailer_len());
+                assert_eq!(&decoded[..sent.len()], sent);
+            }
+        }
+    }
+
+    #[test]
+    fn unexpected_prev_is_last() {
+        let ctrl = 5 | 1 << 31;
+        let input = ctrl_beat(ctrl);
+        let decoded = decode_every_split(|| decoder(false, Integrity::None, false), &input);
+        assert_eq!(
+            decoded.error,
+            Some(C2hError::UnexpectedPrevIsLast {
+                ctrl,
+                state: ProtocolState::NotSet,
+                offset: 0,
+                message: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn invalid_ctrl() {
+        let encoder = encoder(true, Integrity::None, LastEncoding::ThisIsLast);
+        let mut input = encode(&encoder, &[message(3 * PACKET_SIZE)], false);
+        let offset = input.len();
+        input.extend(ctrl_beat(PACKET_SIZE as u32 + 1));
+
+        let decoded = decode_every_split(|| decoder(true, Integrity::None, false), &input);
+        assert_eq!(decoded.messages.len(), 1);
+        assert_eq!(
+            decoded.error,
+            Some(C2hError::InvalidCtrl {
+                ctrl: PACKET_SIZE as u32 + 1,
+                state: ProtocolState::NotSet,
+                offset: offset as u64,
+                message: 1,
+            })
+        );
+
+        // Without escaping, control words are not validated
+        let decoded = decode_every_split(|| decoder(false, Integrity::None, false), &input);
+        assert_eq!(decoded.error, None);
+        assert_eq!(decoded.messages.len(), 2);
+    }
+
+    #[test]
+    fn message_too_large() {
+        let encoder = encoder(false, Integrity::None, LastEncoding::ThisIsLast);
+        let input = encode(&encoder, &[message(2 * PACKET_SIZE + 1)], false);
+
+        let make = || {
+            let mut decoder = decoder(false, Integrity::None, false);
+            decoder.set_max_message_size(Some(2 * PACKET_SIZE));
+            decoder
+        };
+        let decoded = decode_every_split(make, &input);
+        assert_eq!(decoded.partial.len(), 2 * PACKET_SIZE);
+        assert_eq!(
+            decoded.error,
+            Some(C2hError::MessageTooLarge {
+                limit: 2 * PACKET_SIZE,
+                offset: 2 * PACKET_SIZE as u64,
+                message: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn crc_mismatch() {
+        let encoder = encoder(false, Integrity::Crc32c, LastEncoding::ThisIsLast);
+        let mut input = encode(&encoder, &[message(10)], false);
+        input[3] ^= 1;
+        let expected = crc32c::crc32c(&message(10));
+        let mut corrupted = message(10);
+        corrupted[3] ^= 1;
+
+        let decoded = decode_every_split(|| decoder(false, Integrity::Crc32c, false), &input);
+        assert_eq!(
+            decoded.error,
+            Some(C2hError::CrcMismatch {
+                expected,
+                actual: crc32c::crc32c(&corrupted),
+                offset: 0,
+                message: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn missing_trailer() {
+        let encoder = encoder(false, Integrity::None, LastEncoding::ThisIsLast);
+        let input = encode(&encoder, &[message(2)], false);
+
+        let decoded = decode_every_split(|| decoder(false, Integrity::Crc32c, false), &input);
+        assert_eq!(
+            decoded.error,
+            Some(C2hError::MissingTrailer {
+                offset: 0,
+                message: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn missing_header() {
+        let encoder = encoder(false, Integrity::None, LastEncoding::ThisIsLast);
+        let input = encode(&encoder, &[message(2)], false);
+
+        let decoded = decode_every_split(|| decoder(false, Integrity::None, true), &input);
+        assert_eq!(
+            decoded.error,
+            Some(C2hError::MissingHeader {
+                offset: 0,
+                message: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn sequence_mismatch() {
+        let encoder = encoder(false, Integrity::None, LastEncoding::ThisIsLast);
+        let mut input = encode(&encoder, &[message(10)], true);
+        let offset = input.len();
+        let mut second = u32::to_le_bytes(2).to_vec();
+        second.extend(message(10));
+        encoder.encode(&second, &mut input).unwrap();
+
+        let mut decoder = decoder(false, Integrity::None, true);
+        let decoded = decode_split(&mut decoder, &input, input.len());
+        assert_eq!(decoded.messages, [message(10)]);
+        assert_eq!(
+            decoded.error,
+            Some(C2hError::SequenceMismatch {
+                expected: 1,
+                found: 2,
+                offset: offset as u64,
+                message: 1,
+            })
+        );
+
+        // The check continues from the received number
+        let decoded = decode_split(&mut decoder, &input[offset..], input.len() - offset);
+        assert_eq!(decoded.error, None);
+        assert_eq!(decoded.messages, [message(10)]);
+        assert_eq!(decoder.last_sequence(), Some(2));
+    }
+
+    #[test]
+    fn resync_skips_failed_message() {
+        let encoder = encoder(false, Integrity::Crc32c, LastEncoding::ThisIsLast);
+        let messages = [message(10), message(3 * PACKET_SIZE), message(20)];
+        let mut input = encode(&encoder, &messages[..1], false);
+        let corrupted = input.len() + 1;
+        encode(&encoder, &messages[1..], false)
+            .iter()
+            .for_each(|&byte| input.push(byte));
+        input[corrupted] ^= 1;
+
+        for split in 0..input.len() {
+            let mut decoder = decoder(false, Integrity::Crc32c, false);
+            let decoded = decode_split(&mut decoder, &input, split);
+            assert!(matches!(
+                decoded.error,
+                Some(C2hError::CrcMismatch { message: 1, .. })
+            ));
+            assert_eq!(decoder.state(), ProtocolState::Data);
+
+            let rest = &input[decoded.consumed..];
+            let (discarded, resync) = decoder.resync(rest);
+            assert_eq!(resync, Resync::Found);
+            assert_eq!(decoder.state(), ProtocolState::NotSet);
+
+            let rest = &rest[discarded..];
+            let decoded = decode_split(&mut decoder, rest, rest.len());
+            assert_eq!(decoded.error, None);
+            assert_eq!(decoded.messages, [message(20)]);
+        }
+    }
+
+    #[test]
+    fn resync_needs_more() {
+        let mut decoder = decoder(false, Integrity::None, false);
+        assert_eq!(decoder.resync(&[0; 100]), (97, Resync::NeedMore(4)));
+        assert_eq!(decoder.offset(), 97);
+
+        // A control sequence without its control word
+        let mut input = vec![0; 10];
+        input.extend(&ctrl_beat(1)[..PACKET_SIZE]);
+        assert_eq!(
+            decoder.resync(&input),
+            (10, Resync::NeedMore(PACKET_SIZE + CTRL_SIZE))
+        );
+        assert_eq!(decoder.offset(), 107);
+    }
+
+    #[test]
+    fn reset_discards_message() {
+        let encoder = encoder(false, Integrity::Crc32c, LastEncoding::ThisIsLast);
+        let input = encode(&encoder, &[message(3 * PACKET_SIZE)], false);
+        let mut decoder = decoder(false, Integrity::Crc32c, false);
+
+        let (consumed, event) = decoder.decode(&input);
+        assert_eq!(consumed, PACKET_SIZE);
+        assert!(matches!(event, C2hEvent::Data(_)));
+        assert_eq!(decoder.state(), ProtocolState::Data);
+
+        decoder.reset();
+        assert_eq!(decoder.state(), ProtocolState::NotSet);
+        let decoded = decode_split(&mut decoder, &input, input.len());
+        assert_eq!(decoded.error, None);
+        assert_eq!(decoded.messages, [message(3 * PACKET_SIZE)]);
+    }
+
+    #[test]
+    fn reset_discards_pending_end() {
+        let encoder = encoder(false, Integrity::None, LastEncoding::ThisIsLast);
+        let input = encode(&encoder, &[message(10), message(20)], false);
+        let mut decoder = decoder(false, Integrity::None, false);
+
+        let (consumed, event) = decoder.decode(&input);
+        assert_eq!(event, C2hEvent::Data(&message(10)));
+        decoder.reset();
+        let rest = &input[consumed..];
+        let decoded = decode_split(&mut decoder, rest, rest.len());
+        assert_eq!(decoded.messages, [message(20)]);
+    }
+}
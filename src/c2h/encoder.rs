@@ -0,0 +1,269 @@
+use crate::{Integrity, StreamGeometry, CTRL_SEQ};
+use std::{io, ops::Range};
+
+/// Source of the zeros that pad a message for its checksum.
+const ZEROS: [u8; 512] = [0; 512];
+
+/// Encoder producing the C2H beat layout of the card, for test fixtures and software models.
+///
+/// Every packet of a message is sent as a full beat, padded with zeros. With escaping, data beats
+/// starting with [`CTRL_SEQ`] are followed by a zero control word. The end of the message is
+/// marked as chosen with [`LastEncoding`]. A message whose length is an exact multiple of the
+/// packet size ends with a full packet, marked like any other last packet; with
+/// [`set_whole_packets`](Self::set_whole_packets), every message is padded to such a length.
+#[derive(Debug, Clone)]
+pub struct C2hEncoder {
+    packet_size: usize,
+    escape: bool,
+    last_encoding: LastEncoding,
+    integrity: Integrity,
+    whole_packets: bool,
+}
+
+/// How the end of a message is marked by [`C2hEncoder`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum LastEncoding {
+    /// Like the card: a last packet that starts with [`CTRL_SEQ`] carries a ThisIsLast control
+    /// word itself, any other one is followed by a control beat with a PrevIsLast control word.
+    #[default]
+    ThisIsLast,
+    /// Every last packet is followed by a control beat with a PrevIsLast control word. A last
+    /// packet that starts with [`CTRL_SEQ`] is escaped with a zero control word first.
+    PrevIsLast,
+}
+
+impl C2hEncoder {
+    pub fn new(geometry: StreamGeometry) -> Self {
+        Self {
+            packet_size: geometry.packet_size(),
+            escape: false,
+            last_encoding: LastEncoding::ThisIsLast,
+            integrity: Integrity::None,
+            whole_packets: false,
+        }
+    }
+
+    pub fn escape(&self) -> bool {
+        self.escape
+    }
+
+    /// Escapes data beats starting with [`CTRL_SEQ`], like the card does for messages sent with
+    /// [`ESCAPE_FLAG`](crate::ESCAPE_FLAG).
+    pub fn set_escape(&mut self, escape: bool) {
+        self.escape = escape;
+    }
+
+    pub fn last_encoding(&self) -> LastEncoding {
+        self.last_encoding
+    }
+
+    pub fn set_last_encoding(&mut self, last_encoding: LastEncoding) {
+        self.last_encoding = last_encoding;
+    }
+
+    pub fn integrity(&self) -> Integrity {
+        self.integrity
+    }
+
+    /// Sets the integrity check appended to every message before it is encoded.
+    pub fn set_integrity(&mut self, integrity: Integrity) {
+        self.integrity = integrity;
+    }
+
+    pub fn whole_packets(&self) -> bool {
+        self.whole_packets
+    }
+
+    /// Pads every message with zeros, so that together with its trailer it is an exact multiple
+    /// of the packet size. The padding is part of the data of the message and covered by its
+    /// checksum.
+    pub fn set_whole_packets(&mut self, whole_packets: bool) {
+        self.whole_packets = whole_packets;
+    }
+
+    /// Appends the beats of `message` to `out`.
+    ///
+    /// Fails with an error of kind [`InvalidInput`](io::ErrorKind::InvalidInput) before anything
+    /// is appended if `message` is empty, the card never sends empty messages.
+    pub fn encode<E>(&self, message: &[u8], out: &mut E) -> io::Result<()>
+    where
+        E: for<'a> Extend<&'a u8>,
+    {
+        if message.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the card never sends empty messages",
+            ));
+        }
+
+        let trailer_len = self.integrity.trailer_len();
+        let padding = match self.whole_packets {
+            true => {
+                (message.len() + trailer_len).next_multiple_of(self.packet_size)
+                    - trailer_len
+                    - message.len()
+            }
+            false => 0,
+        };
+        let mut trailer = Vec::with_capacity(trailer_len);
+        if let Integrity::Crc32c = self.integrity {
+            let mut crc = crc32c::crc32c(message);
+            let mut rest = padding;
+            while rest > 0 {
+                let len = usize::min(rest, ZEROS.len());
+                crc = crc32c::crc32c_append(crc, &ZEROS[..len]);
+                rest -= len;
+            }
+            trailer.extend_from_slice(&u32::to_le_bytes(crc));
+        }
+        let framed = Framed {
+            message,
+            padding,
+            trailer: &trailer,
+        };
+
+        let len = framed.len();
+        for start in (0..len).step_by(self.packet_size) {
+            let end = usize::min(start + self.packet_size, len);
+            let packet_len = end - start;
+            framed.extend(start..end, out);
+            out.extend(std::iter::repeat_n(&0, self.packet_size - packet_len));
+
+            let mut head = Vec::with_capacity(CTRL_SEQ.len());
+            framed.extend(start..usize::min(start + CTRL_SEQ.len(), end), &mut head);
+            let starts_with_ctrl = head == CTRL_SEQ;
+            let is_last = end == len;
+            if !is_last {
+                if self.escape && starts_with_ctrl {
+                    out.extend(&u32::to_le_bytes(0));
+                }
+            } else if starts_with_ctrl && self.last_encoding == LastEncoding::ThisIsLast {
+                out.extend(&u32::to_le_bytes(packet_len as u32));
+            } else {
+                if starts_with_ctrl {
+                    out.extend(&u32::to_le_bytes(0));
+                }
+                out.extend(&CTRL_SEQ);
+                out.extend(std::iter::repeat_n(&0, self.packet_size - CTRL_SEQ.len()));
+                out.extend(&u32::to_le_bytes(packet_len as u32 | (1 << 31)));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A message followed by its padding and trailer, which are encoded without joining them first.
+struct Framed<'a> {
+    message: &'a [u8],
+    padding: usize,
+    trailer: &'a [u8],
+}
+
+impl Framed<'_> {
+    fn len(&self) -> usize {
+        self.message.len() + self.padding + self.trailer.len()
+    }
+
+    /// Appends the bytes in `range` to `out`.
+    fn extend<E>(&self, range: Range<usize>, out: &mut E)
+    where
+        E: for<'a> Extend<&'a u8>,
+    {
+        // Part of `range` within the `len` bytes starting at `start`
+        let within = |start: usize, len: usize| {
+            let clamp = |pos: usize| pos.clamp(start, start + len) - start;
+            clamp(range.start)..clamp(range.end)
+        };
+
+        out.extend(&self.message[within(0, self.message.len())]);
+        let padding = within(self.message.len(), self.padding);
+        out.extend(std::iter::repeat_n(&0, padding.len()));
+        let trailer = within(self.message.len() + self.padding, self.trailer.len());
+        out.extend(&self.trailer[trailer]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CTRL_SIZE;
+
+    fn encoder(integrity: Integrity, whole_packets: bool) -> C2hEncoder {
+        let mut encoder = C2hEncoder::new(StreamGeometry::new(64, 64).unwrap());
+        encoder.set_integrity(integrity);
+        encoder.set_whole_packets(whole_packets);
+        encoder
+    }
+
+    /// The packets of the message and the length carried by its PrevIsLast control word.
+    fn split(beats: &[u8]) -> (&[u8], u32) {
+        let (data, ctrl) = beats.split_at(beats.len() - 64 - CTRL_SIZE);
+        assert!(ctrl.starts_with(&CTRL_SEQ));
+        let word = u32::from_le_bytes(ctrl[64..].try_into().unwrap());
+        assert_ne!(word & (1 << 31), 0);
+        (data, word & !(1 << 31))
+    }
+
+    #[test]
+    fn empty_message_rejected() {
+        let mut beats = Vec::new();
+        let err = encoder(Integrity::None, false)
+            .encode(&[], &mut beats)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(beats.is_empty());
+    }
+
+    #[test]
+    fn exact_multiple_ends_with_full_packet() {
+        let mut beats = Vec::new();
+        encoder(Integrity::None, false)
+            .encode(&[1; 128], &mut beats)
+            .unwrap();
+        let (data, len) = split(&beats);
+        assert_eq!(data, &[1; 128]);
+        assert_eq!(len, 64);
+    }
+
+    #[test]
+    fn whole_packets_pad_message() {
+        let mut beats = Vec::new();
+        encoder(Integrity::None, true)
+            .encode(&[1; 65], &mut beats)
+            .unwrap();
+        let (data, len) = split(&beats);
+        assert_eq!(data.len(), 128);
+        assert_eq!(&data[..65], &[1; 65]);
+        assert!(data[65..].iter().all(|&byte| byte == 0));
+        assert_eq!(len, 64);
+    }
+
+    #[test]
+    fn whole_packets_include_trailer() {
+        let mut beats = Vec::new();
+        encoder(Integrity::Crc32c, true)
+            .encode(&[1; 10], &mut beats)
+            .unwrap();
+        let (data, len) = split(&beats);
+        assert_eq!(data.len(), 64);
+        assert_eq!(len, 64);
+        let crc = crc32c::crc32c(&data[..60]);
+        assert_eq!(data[60..], u32::to_le_bytes(crc));
+    }
+
+    #[test]
+    fn trailer_spans_packets() {
+        let mut beats = Vec::new();
+        encoder(Integrity::Crc32c, false)
+            .encode(&[1; 62], &mut beats)
+            .unwrap();
+        let (data, len) = split(&beats);
+        assert_eq!(data.len(), 128);
+        assert_eq!(len, 2);
+        let crc = u32::to_le_bytes(crc32c::crc32c(&[1; 62]));
+        assert_eq!(data[62..64], crc[..2]);
+        assert_eq!(data[64..66], crc[2..]);
+        assert!(data[66..].iter().all(|&byte| byte == 0));
+    }
+}
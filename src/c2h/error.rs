@@ -0,0 +1,180 @@
+use super::ProtocolState;
+use std::{error::Error, fmt, io};
+
+/// Errors reported by [`CardToHostStream`](super::CardToHostStream) while decoding the C2H framing.
+///
+/// They are returned wrapped in an [`io::Error`] of kind [`InvalidData`](io::ErrorKind::InvalidData)
+/// and can be recovered with [`io::Error::get_ref`] and `downcast_ref`.
+/// [`Poisoned`](Self::Poisoned) is wrapped with kind [`Other`](io::ErrorKind::Other).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum C2hError {
+    /// A PrevIsLast control word arrived without a preceding data packet.
+    UnexpectedPrevIsLast {
+        /// Raw control word.
+        ctrl: u32,
+        /// Decoder state when the control word was read.
+        state: ProtocolState,
+        /// Byte offset of the control beat in the stream.
+        offset: u64,
+        /// Index of the message that was being decoded.
+        message: u64,
+    },
+    /// A control word that is not valid for the packet size, only reported if escaping is
+    /// enabled.
+    InvalidCtrl {
+        /// Raw control word.
+        ctrl: u32,
+        /// Decoder state when the control word was read.
+        state: ProtocolState,
+        /// Byte offset of the control beat in the stream.
+        offset: u64,
+        /// Index of the message that was being decoded.
+        message: u64,
+    },
+    /// The message grew beyond the configured maximum size. The packet that would exceed the
+    /// limit is not consumed, use [`resync`](super::CardToHostStream::resync) to skip the rest of
+    /// the message.
+    MessageTooLarge {
+        /// Maximum message size in bytes.
+        limit: usize,
+        /// Byte offset of the packet that would exceed the limit.
+        offset: u64,
+        /// Index of the message that was being decoded.
+        message: u64,
+    },
+    /// The CRC32C trailer does not match the data of the message. The message end is not
+    /// consumed, use [`resync`](super::CardToHostStream::resync) to skip it.
+    CrcMismatch {
+        /// Checksum carried by the trailer.
+        expected: u32,
+        /// Checksum computed over the received data.
+        actual: u32,
+        /// Byte offset of the last beat of the message.
+        offset: u64,
+        /// Index of the message.
+        message: u64,
+    },
+    /// The message is too short to hold the trailer required by the
+    /// [`Integrity`](crate::Integrity) mode.
+    MissingTrailer {
+        /// Byte offset of the last beat of the message.
+        offset: u64,
+        /// Index of the message.
+        message: u64,
+    },
+    /// The message is too short to hold the sequence number.
+    MissingHeader {
+        /// Byte offset of the first beat of the message.
+        offset: u64,
+        /// Index of the message.
+        message: u64,
+    },
+    /// The sequence number of a message is not the successor of the previous one, messages were
+    /// dropped, duplicated or reordered. The message is not consumed and will be returned by the
+    /// next read, the sequence check continues from its number.
+    SequenceMismatch {
+        /// Sequence number following the one of the previous message.
+        expected: u32,
+        /// Sequence number carried by the message.
+        found: u32,
+        /// Byte offset of the first beat of the message.
+        offset: u64,
+        /// Index of the message.
+        message: u64,
+    },
+    /// A read from the device failed in the middle of a message, after some of its packets were
+    /// returned. The stream refuses to read on, because the rest would be taken for a message of
+    /// its own, until it is recovered with [`resync`](super::CardToHostStream::resync) or
+    /// [`reset`](super::CardToHostStream::reset).
+    Poisoned {
+        /// Byte offset in the stream where the read failed.
+        offset: u64,
+        /// Index of the message that was being read.
+        message: u64,
+    },
+}
+
+impl fmt::Display for C2hError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnexpectedPrevIsLast {
+                ctrl,
+                state,
+                offset,
+                message,
+            } => write!(
+                f,
+                "protocol error: unexpected PrevIsLast control word {:#010x} in state {:?} \
+                 at offset {} (message {})",
+                ctrl, state, offset, message,
+            ),
+            Self::InvalidCtrl {
+                ctrl,
+                state,
+                offset,
+                message,
+            } => write!(
+                f,
+                "protocol error: invalid control word {:#010x} in state {:?} at offset {} \
+                 (message {})",
+                ctrl, state, offset, message,
+            ),
+            Self::MessageTooLarge {
+                limit,
+                offset,
+                message,
+            } => write!(
+                f,
+                "message {} exceeds the limit of {} bytes at offset {}",
+                message, limit, offset,
+            ),
+            Self::CrcMismatch {
+                expected,
+                actual,
+                offset,
+                message,
+            } => write!(
+                f,
+                "crc mismatch in message {} at offset {}: expected {:#010x}, got {:#010x}",
+                message, offset, expected, actual,
+            ),
+            Self::MissingTrailer { offset, message } => write!(
+                f,
+                "message {} at offset {} is too short for its trailer",
+                message, offset,
+            ),
+            Self::MissingHeader { offset, message } => write!(
+                f,
+                "message {} at offset {} is too short for its sequence number",
+                message, offset,
+            ),
+            Self::SequenceMismatch {
+                expected,
+                found,
+                offset,
+                message,
+            } => write!(
+                f,
+                "sequence mismatch in message {} at offset {}: expected {}, found {}",
+                message, offset, expected, found,
+            ),
+            Self::Poisoned { offset, message } => write!(
+                f,
+                "stream poisoned by a failed read in message {} at offset {}",
+                message, offset,
+            ),
+        }
+    }
+}
+
+impl Error for C2hError {}
+
+impl From<C2hError> for io::Error {
+    fn from(err: C2hError) -> Self {
+        let kind = match err {
+            C2hError::Poisoned { .. } => io::ErrorKind::Other,
+            _ => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, err)
+    }
+}
@@ -0,0 +1,100 @@
+use super::CardToHostStream;
+use std::{
+    io::{self, BufRead, Read},
+    ptr,
+};
+
+/// Reads a single framed message from a [`CardToHostStream`].
+///
+/// Returned by [`CardToHostStream::next_message`]. Reports end of file at the message boundary
+/// only. After an error, a read tries again where the failed one stopped, which continues the
+/// message after a read that timed out or would block. Any unread rest of the message is drained
+/// when the reader is dropped, so unless that fails, the stream is left at the start of the next
+/// message.
+pub struct MessageReader<'a, F>
+where
+    F: Read,
+{
+    stream: &'a mut CardToHostStream<F>,
+    packet: *const u8,
+    len: usize,
+    pos: usize,
+    is_last: bool,
+    finished: bool,
+}
+
+impl<'a, F> MessageReader<'a, F>
+where
+    F: Read,
+{
+    pub(super) fn new(stream: &'a mut CardToHostStream<F>) -> Self {
+        Self {
+            stream,
+            packet: ptr::null(),
+            len: 0,
+            pos: 0,
+            is_last: false,
+            finished: false,
+        }
+    }
+
+    /// Reads the next packet of the message. An error leaves the reader where it was, so that
+    /// the next call tries again, e.g. after a read that would block.
+    fn next_packet(&mut self) -> io::Result<()> {
+        let (is_last, packet) = self.stream.next_stream_packet()?;
+        self.packet = packet.as_ptr();
+        self.len = packet.len();
+        self.pos = 0;
+        self.is_last = is_last;
+        Ok(())
+    }
+}
+
+impl<F> Read for MessageReader<'_, F>
+where
+    F: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let count = usize::min(buf.len(), available.len());
+        buf[..count].copy_from_slice(&available[..count]);
+        self.consume(count);
+        Ok(count)
+    }
+}
+
+impl<F> BufRead for MessageReader<'_, F>
+where
+    F: Read,
+{
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        while self.pos == self.len {
+            if self.is_last || self.finished {
+                self.finished = true;
+                return Ok(&[]);
+            }
+            self.next_packet()?;
+        }
+
+        // The packet points into the buffers of the stream, which are only touched again by the
+        // next call to `next_stream_packet`.
+        Ok(unsafe { std::slice::from_raw_parts(self.packet.add(self.pos), self.len - self.pos) })
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = usize::min(self.pos + amt, self.len);
+    }
+}
+
+impl<F> Drop for MessageReader<'_, F>
+where
+    F: Read,
+{
+    fn drop(&mut self) {
+        while !self.finished && !self.is_last {
+            if self.next_packet().is_err() {
+                break;
+            }
+        }
+    }
+}
@@ -1,48 +1,132 @@
-use crate::{
-    util::{mem_aligned, mem_aligned_free},
-    ALIGN, CTRL_SIZE, PACKET_SIZE,
+#[cfg(feature = "tokio")]
+mod async_stream;
+mod buf;
+mod decoder;
+mod encoder;
+mod error;
+mod message;
+mod timeout;
+
+#[cfg(feature = "tokio")]
+pub use self::async_stream::AsyncCardToHostStream;
+pub use self::{
+    decoder::{C2hDecoder, C2hEvent, Resync},
+    encoder::{C2hEncoder, LastEncoding},
+    error::C2hError,
+    message::MessageReader,
 };
+
+use self::{buf::ReadBuf, decoder::Step};
+use crate::{EscapeMode, Integrity, StreamGeometry, CTRL_SIZE};
 use anyhow::Result;
-use std::{
-    io::{self, Read, Write},
-    ptr::NonNull,
-};
+use std::io::{self, Read, Write};
 
-unsafe impl<F> Send for CardToHostStream<F> {}
-unsafe impl<F> Sync for CardToHostStream<F> {}
+/// Size of the region read from the device in one go.
+const DEFAULT_READ_CAPACITY: usize = 2 * 1024 * 1024;
+
+/// Beats the decoder may need at once: a packet, the beat telling whether it ends the message
+/// and, with a trailer, the beat after that. Integrity can be enabled after construction, so the
+/// read buffer must always hold three.
+const BEATS_AHEAD: usize = 3;
 
 pub struct CardToHostStream<F> {
     file: F,
-    ptr: NonNull<u8>,
-    ptr_prev: NonNull<u8>,
-    ptr_ctrl: NonNull<u8>,
-
-    protocol_state: ProtocolState,
+    buf: ReadBuf,
+    decoder: C2hDecoder,
+    geometry: StreamGeometry,
+    poisoned: bool,
 }
 
 impl<F> CardToHostStream<F> {
     pub fn new(file: F) -> Result<Self> {
-        let ptr = mem_aligned(PACKET_SIZE, ALIGN)?;
-        let slice = unsafe { std::slice::from_raw_parts_mut(ptr.as_ptr(), PACKET_SIZE) };
-        slice.copy_from_slice(&[0; PACKET_SIZE]);
+        Self::with_capacity(file, DEFAULT_READ_CAPACITY)
+    }
 
-        let ptr_prev = mem_aligned(PACKET_SIZE, ALIGN)?;
-        let slice = unsafe { std::slice::from_raw_parts_mut(ptr_prev.as_ptr(), PACKET_SIZE) };
-        slice.copy_from_slice(&[0; PACKET_SIZE]);
+    /// Creates a stream that reads up to `capacity` bytes from `file` per call. `capacity` must be
+    /// a multiple of [`ALIGN`](crate::ALIGN) and hold at least three beats.
+    pub fn with_capacity(file: F, capacity: usize) -> Result<Self> {
+        Self::with_geometry(file, capacity, StreamGeometry::default())
+    }
 
-        let ptr_ctrl = mem_aligned(CTRL_SIZE, ALIGN)?;
-        let slice = unsafe { std::slice::from_raw_parts_mut(ptr_ctrl.as_ptr(), CTRL_SIZE) };
-        slice.copy_from_slice(&[0; CTRL_SIZE]);
+    /// Like [`with_capacity`](Self::with_capacity), but for a queue with the given packet size
+    /// and alignment.
+    pub fn with_geometry(file: F, capacity: usize, geometry: StreamGeometry) -> Result<Self> {
+        anyhow::ensure!(
+            capacity >= BEATS_AHEAD * (geometry.packet_size() + CTRL_SIZE),
+            "capacity too small"
+        );
 
         Ok(Self {
             file,
-            ptr,
-            ptr_prev,
-            ptr_ctrl,
-
-            protocol_state: ProtocolState::NotSet,
+            buf: ReadBuf::new(capacity, geometry.align())?,
+            decoder: C2hDecoder::new(geometry),
+            geometry,
+            poisoned: false,
         })
     }
+
+    pub fn geometry(&self) -> StreamGeometry {
+        self.geometry
+    }
+
+    pub fn escape_mode(&self) -> EscapeMode {
+        self.decoder.escape_mode()
+    }
+
+    pub fn integrity(&self) -> Integrity {
+        self.decoder.integrity()
+    }
+
+    /// Sets the integrity check the card appends to every message. With [`Integrity::Crc32c`],
+    /// a message whose trailer does not match fails with [`C2hError::CrcMismatch`] in place of
+    /// its last packet.
+    pub fn set_integrity(&mut self, integrity: Integrity) {
+        self.decoder.set_integrity(integrity);
+    }
+
+    pub fn max_message_size(&self) -> Option<usize> {
+        self.decoder.max_message_size()
+    }
+
+    /// Limits the size of a message. A message that grows beyond the limit fails with
+    /// [`C2hError::MessageTooLarge`] instead of being read any further.
+    pub fn set_max_message_size(&mut self, max: Option<usize>) {
+        self.decoder.set_max_message_size(max);
+    }
+
+    /// Sets how beats starting with [`CTRL_SEQ`](crate::CTRL_SEQ) are handled. See
+    /// [`EscapeMode`].
+    pub fn set_escape_mode(&mut self, mode: EscapeMode) {
+        self.decoder.set_escape_mode(mode);
+    }
+
+    pub fn sequence_numbers(&self) -> bool {
+        self.decoder.sequence_numbers()
+    }
+
+    /// Expects the sequence number written by
+    /// [`HostToCardStream::set_sequence_numbers`](crate::HostToCardStream::set_sequence_numbers)
+    /// in front of every message and strips it from the data. A message that does not carry the
+    /// successor of the previous number fails with [`C2hError::SequenceMismatch`] once.
+    pub fn set_sequence_numbers(&mut self, enabled: bool) {
+        self.decoder.set_sequence_numbers(enabled);
+    }
+
+    /// Sequence number of the last message whose first packet was read.
+    pub fn last_sequence(&self) -> Option<u32> {
+        self.decoder.last_sequence()
+    }
+
+    /// Whether a read from the file failed in the middle of a message, after some of its packets
+    /// were returned. A poisoned stream fails every read of messages with
+    /// [`C2hError::Poisoned`], until [`resync`](Self::resync) skips the rest of the message or
+    /// [`reset`](Self::reset) starts over.
+    ///
+    /// A read that times out or would block does not poison the stream, because nothing is lost
+    /// and repeating the call continues the message.
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned
+    }
 }
 
 impl<F> CardToHostStream<F>
@@ -50,21 +134,46 @@ where
     F: Read,
 {
     pub fn next_raw_packet_with_len(&mut self, len: usize) -> io::Result<&[u8]> {
-        let len = usize::min(len, PACKET_SIZE);
-        let slice = unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), len) };
-
-        self.file.read_exact(slice)?;
-        Ok(slice)
+        let len = usize::min(len, self.geometry.packet_size());
+        self.buf.fill_from(&mut self.file, len)?;
+        self.decoder.skip(len);
+        Ok(self.buf.take(len, 0..len))
     }
 
     pub fn next_raw_packet(&mut self) -> io::Result<&[u8]> {
-        self.next_raw_packet_with_len(PACKET_SIZE)
+        self.next_raw_packet_with_len(self.geometry.packet_size())
+    }
+
+    /// Like [`read_complete_stream`](Self::read_complete_stream), but fails with
+    /// [`C2hError::MessageTooLarge`] once the message exceeds `max_bytes`, in addition to the
+    /// limit set with [`set_max_message_size`](Self::set_max_message_size).
+    pub fn read_complete_stream_limited(
+        &mut self,
+        buf: impl Write,
+        max_bytes: usize,
+    ) -> io::Result<usize> {
+        let max_message_size = self.decoder.max_message_size();
+        let limit = max_message_size.map_or(max_bytes, |max| usize::min(max, max_bytes));
+
+        self.decoder.set_max_message_size(Some(limit));
+        let res = self.read_complete_stream(buf);
+        self.decoder.set_max_message_size(max_message_size);
+
+        res
     }
 
-    pub fn read_complete_stream(&mut self, mut buf: impl Write) -> io::Result<usize> {
+    pub fn read_complete_stream(&mut self, buf: impl Write) -> io::Result<usize> {
+        self.read_complete_stream_with(buf, |buf, file, len| buf.fill_from(file, len))
+    }
+
+    fn read_complete_stream_with(
+        &mut self,
+        mut buf: impl Write,
+        mut fill: impl FnMut(&mut ReadBuf, &mut F, usize) -> io::Result<()>,
+    ) -> io::Result<usize> {
         let mut bytes = 0;
         loop {
-            let (is_last, packet) = self.next_stream_packet()?;
+            let (is_last, packet) = self.next_stream_packet_with(&mut fill)?;
             buf.write_all(packet)?;
             bytes += packet.len();
             if is_last {
@@ -73,99 +182,259 @@ where
         }
     }
 
-    /// Returns `(is_last, data)`
-    pub fn next_stream_packet(&mut self) -> io::Result<(bool, &[u8])> {
-        // Read previous packet
-        let slice_prev =
-            unsafe { std::slice::from_raw_parts_mut(self.ptr_prev.as_ptr(), PACKET_SIZE) };
-        match self.protocol_state {
-            ProtocolState::NotSet => {
-                self.protocol_state = ProtocolState::Data;
-                match self.next_beat_protocol(slice_prev)? {
-                    BeatMeta::ThisIsData => (),
-                    BeatMeta::ThisIsLast(len) => {
-                        self.protocol_state = ProtocolState::NotSet;
-                        return Ok((true, &slice_prev[..len]));
-                    }
-                    BeatMeta::PrevIsLast(_) => {
-                        return Err(io::Error::new(io::ErrorKind::InvalidData, "protocol error"));
-                    }
+    /// Returns a reader over the next message. See [`MessageReader`].
+    pub fn next_message(&mut self) -> MessageReader<'_, F> {
+        MessageReader::new(self)
+    }
+
+    /// Discards data up to and including the next control beat that ends a message, so that
+    /// decoding can continue after a protocol or I/O error. Returns the number of discarded bytes.
+    ///
+    /// Control words are validated regardless of the [`EscapeMode`]. Finding the end of a message
+    /// recovers a [poisoned](Self::is_poisoned) stream.
+    pub fn resync(&mut self) -> io::Result<u64> {
+        let mut discarded = 0;
+        loop {
+            let (consumed, resync) = self.decoder.resync(self.buf.data());
+            self.buf.consume(consumed);
+            discarded += consumed as u64;
+            match resync {
+                Resync::Found => {
+                    self.poisoned = false;
+                    break Ok(discarded);
                 }
-            }
-            ProtocolState::Data => (),
-            ProtocolState::Last(len) => {
-                self.protocol_state = ProtocolState::NotSet;
-                let slice =
-                    unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), PACKET_SIZE) };
-                return Ok((true, &slice[..len]));
+                Resync::NeedMore(len) => self.buf.fill_from(&mut self.file, len)?,
             }
         }
+    }
 
-        // Read current packet
-        let slice = unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), PACKET_SIZE) };
-        match self.next_beat_protocol(slice)? {
-            BeatMeta::ThisIsData => {
-                // Swap pointers
-                std::mem::swap(&mut self.ptr, &mut self.ptr_prev);
-            }
-            BeatMeta::ThisIsLast(len) => self.protocol_state = ProtocolState::Last(len),
-            BeatMeta::PrevIsLast(len) => {
-                self.protocol_state = ProtocolState::NotSet;
-                return Ok((true, &slice_prev[..len]));
-            }
-        }
+    /// Discards all buffered data and returns the decoder to the start of a message.
+    ///
+    /// Use this after the queue has been drained or restarted, so that the next byte read from
+    /// the file starts a message. This also recovers a [poisoned](Self::is_poisoned) stream.
+    pub fn reset(&mut self) {
+        let len = self.buf.data().len();
+        self.buf.consume(len);
+        self.decoder.skip(len);
+        self.decoder.reset();
+        self.poisoned = false;
+    }
 
-        Ok((false, slice_prev))
+    /// Returns `(is_last, data)`
+    pub fn next_stream_packet(&mut self) -> io::Result<(bool, &[u8])> {
+        self.next_stream_packet_with(|buf, file, len| buf.fill_from(file, len))
     }
 
-    fn next_beat_protocol(&mut self, slice: &mut [u8]) -> io::Result<BeatMeta> {
-        self.file.read_exact(slice)?;
-        if slice.starts_with(&CTRL_SEQ) {
-            self.read_ctrl()
-        } else {
-            Ok(BeatMeta::ThisIsData)
+    /// Decodes the next packet, `fill` buffers at least the given number of bytes.
+    fn next_stream_packet_with(
+        &mut self,
+        mut fill: impl FnMut(&mut ReadBuf, &mut F, usize) -> io::Result<()>,
+    ) -> io::Result<(bool, &[u8])> {
+        check_poisoned(&self.decoder, self.poisoned)?;
+        loop {
+            match next_packet(&mut self.decoder, self.buf.data())? {
+                Step::NeedMore(len) => {
+                    if let Err(err) = fill(&mut self.buf, &mut self.file, len) {
+                        self.poisoned = poisons(&self.decoder, &err);
+                        return Err(err);
+                    }
+                }
+                Step::Packet {
+                    is_last,
+                    data,
+                    consumed,
+                } => return Ok((is_last, self.buf.take(consumed, data))),
+            }
         }
     }
+}
 
-    fn read_ctrl(&mut self) -> io::Result<BeatMeta> {
-        let slice_ctrl =
-            unsafe { std::slice::from_raw_parts_mut(self.ptr_ctrl.as_ptr(), CTRL_SIZE) };
-        self.file.read_exact(slice_ctrl)?;
-        let ctrl = u32::from_le_bytes([slice_ctrl[0], slice_ctrl[1], slice_ctrl[2], slice_ctrl[3]]);
-
-        Ok(if ctrl == 0 {
-            BeatMeta::ThisIsData
-        } else if (ctrl & (1 << 31)) == 0 {
-            let len = usize::min(PACKET_SIZE, ctrl as usize);
-            BeatMeta::ThisIsLast(len)
-        } else {
-            let len = usize::min(PACKET_SIZE, (ctrl & !(1 << 31)) as usize);
-            BeatMeta::PrevIsLast(len)
-        })
+/// Decodes the next packet from the events of `decoder`.
+fn next_packet(decoder: &mut C2hDecoder, input: &[u8]) -> Result<Step, C2hError> {
+    let (consumed, event) = decoder.decode(input);
+    match event {
+        C2hEvent::Data(data) => {
+            let start = data.as_ptr() as usize - input.as_ptr() as usize;
+            // The end of the message follows the data of its last packet without more input
+            let is_last = matches!(decoder.decode(&[]).1, C2hEvent::MessageEnd { .. });
+            Ok(Step::Packet {
+                is_last,
+                data: start..start + data.len(),
+                consumed,
+            })
+        }
+        C2hEvent::MessageEnd { .. } => Ok(Step::Packet {
+            is_last: true,
+            data: 0..0,
+            consumed,
+        }),
+        C2hEvent::NeedMore(len) => Ok(Step::NeedMore(len)),
+        C2hEvent::Error(err) => Err(err),
     }
 }
 
-impl<F> Drop for CardToHostStream<F> {
-    fn drop(&mut self) {
-        unsafe {
-            mem_aligned_free(self.ptr.as_ptr(), PACKET_SIZE, ALIGN);
-            mem_aligned_free(self.ptr_ctrl.as_ptr(), CTRL_SIZE, ALIGN);
-        }
+/// Fails with [`C2hError::Poisoned`] if `poisoned` is set.
+fn check_poisoned(decoder: &C2hDecoder, poisoned: bool) -> Result<(), C2hError> {
+    match poisoned {
+        true => Err(C2hError::Poisoned {
+            offset: decoder.offset(),
+            message: decoder.message(),
+        }),
+        false => Ok(()),
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-enum ProtocolState {
+/// Whether the failed read `err` poisons a stream: it interrupted a message whose packets were
+/// returned, and the read cannot simply be repeated.
+fn poisons(decoder: &C2hDecoder, err: &io::Error) -> bool {
+    let retry = matches!(
+        err.kind(),
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+    );
+    decoder.state() == ProtocolState::Data && !retry
+}
+
+/// State of the C2H decoder between two packets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolState {
+    /// No message is in progress.
     NotSet,
+    /// Data packets of a message have been returned, but not its last one.
     Data,
-    Last(usize),
 }
 
-#[derive(Debug, Clone, Copy)]
-enum BeatMeta {
-    ThisIsData,
-    ThisIsLast(usize),
-    PrevIsLast(usize),
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::message;
+
+    const PACKET_SIZE: usize = 64;
+
+    /// File that returns at most a packet per read and fails the read at `fail_at` once.
+    struct Flaky {
+        data: Vec<u8>,
+        pos: usize,
+        fail_at: usize,
+        kind: io::ErrorKind,
+    }
+
+    impl Read for Flaky {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.pos == self.fail_at {
+                self.fail_at = usize::MAX;
+                return Err(self.kind.into());
+            }
+            let end = usize::min(self.pos + PACKET_SIZE, self.data.len());
+            let end = usize::min(end, usize::max(self.fail_at, self.pos));
+            let count = usize::min(buf.len(), end - self.pos);
+            buf[..count].copy_from_slice(&self.data[self.pos..self.pos + count]);
+            self.pos += count;
+            Ok(count)
+        }
+    }
+
+    /// Stream over a 3 packet and a 10 byte message, whose file fails with `kind` at `fail_at`.
+    fn stream(kind: io::ErrorKind, fail_at: usize) -> CardToHostStream<Flaky> {
+        let geometry = StreamGeometry::new(PACKET_SIZE, 64).unwrap();
+        let encoder = C2hEncoder::new(geometry);
+        let mut data = Vec::new();
+        for message in [message(3 * PACKET_SIZE), message(10)] {
+            encoder.encode(&message, &mut data).unwrap();
+        }
+        let file = Flaky {
+            data,
+            pos: 0,
+            fail_at,
+            kind,
+        };
+        CardToHostStream::with_geometry(file, 256, geometry).unwrap()
+    }
+
+    fn poisoned(err: io::Error) -> C2hError {
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        err.into_inner()
+            .unwrap()
+            .downcast::<C2hError>()
+            .map(|err| *err)
+            .unwrap()
+    }
+
+    /// The first packet of the first message is returned before the read fails.
+    const MID_MESSAGE: usize = 2 * PACKET_SIZE;
+
+    #[test]
+    fn failed_read_mid_message_poisons() {
+        let mut stream = stream(io::ErrorKind::BrokenPipe, MID_MESSAGE);
+        let mut received = Vec::new();
+        let err = stream.read_complete_stream(&mut received).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(received, message(3 * PACKET_SIZE)[..PACKET_SIZE]);
+        assert!(stream.is_poisoned());
+
+        let err = stream.read_complete_stream(Vec::new()).unwrap_err();
+        assert_eq!(
+            poisoned(err),
+            C2hError::Poisoned {
+                offset: PACKET_SIZE as u64,
+                message: 0,
+            }
+        );
+        assert!(stream.next_message().read_to_end(&mut Vec::new()).is_err());
+
+        // The rest of the first message is skipped
+        stream.resync().unwrap();
+        assert!(!stream.is_poisoned());
+        let mut received = Vec::new();
+        stream.read_complete_stream(&mut received).unwrap();
+        assert_eq!(received, message(10));
+    }
 
-const CTRL_SEQ: [u8; 4] = [0x5C, 0xF1, 0x37, 0x4A];
+    #[test]
+    fn reset_recovers_poisoned() {
+        let mut stream = stream(io::ErrorKind::BrokenPipe, MID_MESSAGE);
+        stream.read_complete_stream(Vec::new()).unwrap_err();
+        assert!(stream.is_poisoned());
+        stream.reset();
+        assert!(!stream.is_poisoned());
+    }
+
+    #[test]
+    fn timeout_mid_message_continues() {
+        let mut stream = stream(io::ErrorKind::TimedOut, MID_MESSAGE);
+        let mut received = Vec::new();
+        let err = stream.read_complete_stream(&mut received).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(!stream.is_poisoned());
+
+        stream.read_complete_stream(&mut received).unwrap();
+        assert_eq!(received, message(3 * PACKET_SIZE));
+    }
+
+    #[test]
+    fn failed_read_between_messages_does_not_poison() {
+        let mut stream = stream(io::ErrorKind::BrokenPipe, 0);
+        assert!(stream.read_complete_stream(Vec::new()).is_err());
+        assert!(!stream.is_poisoned());
+        let mut received = Vec::new();
+        stream.read_complete_stream(&mut received).unwrap();
+        assert_eq!(received, message(3 * PACKET_SIZE));
+    }
+
+    #[test]
+    fn message_reader_resumes_after_would_block() {
+        let mut stream = stream(io::ErrorKind::WouldBlock, MID_MESSAGE);
+        let mut reader = stream.next_message();
+        let mut received = Vec::new();
+        let err = reader.read_to_end(&mut received).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(received, message(3 * PACKET_SIZE)[..PACKET_SIZE]);
+
+        reader.read_to_end(&mut received).unwrap();
+        assert_eq!(received, message(3 * PACKET_SIZE));
+        drop(reader);
+
+        let mut received = Vec::new();
+        stream.next_message().read_to_end(&mut received).unwrap();
+        assert_eq!(received, message(10));
+    }
+}
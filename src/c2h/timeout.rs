@@ -0,0 +1,188 @@
+use super::CardToHostStream;
+use std::{
+    io::{self, Read, Write},
+    os::fd::{AsRawFd, RawFd},
+    time::{Duration, Instant},
+};
+
+impl<F> CardToHostStream<F>
+where
+    F: Read + AsRawFd,
+{
+    /// Like [`next_stream_packet`](Self::next_stream_packet), but fails with
+    /// [`TimedOut`](io::ErrorKind::TimedOut) if the packet is not complete within `timeout`.
+    ///
+    /// The deadline is checked before every read from the file, which asks for the free capacity
+    /// of the stream and takes what is available. A read that has started is not interrupted, so
+    /// on a file whose reads block until the whole length arrives, the timeout only applies while
+    /// no data is available at all.
+    ///
+    /// Bytes read before the timeout stay buffered and the decoder keeps its state, so the call
+    /// can simply be repeated.
+    pub fn next_stream_packet_timeout(&mut self, timeout: Duration) -> io::Result<(bool, &[u8])> {
+        let deadline = Instant::now() + timeout;
+        self.next_stream_packet_with(|buf, file, len| {
+            buf.fill_from(Deadline { file, deadline }, len)
+        })
+    }
+
+    /// Like [`read_complete_stream`](Self::read_complete_stream), but fails with
+    /// [`TimedOut`](io::ErrorKind::TimedOut) if the message is not complete within `timeout`.
+    ///
+    /// Packets written to `buf` before the timeout are not written again. Repeating the call
+    /// continues with the rest of the message. The deadline is checked like by
+    /// [`next_stream_packet_timeout`](Self::next_stream_packet_timeout).
+    pub fn read_complete_stream_timeout(
+        &mut self,
+        buf: impl Write,
+        timeout: Duration,
+    ) -> io::Result<usize> {
+        let deadline = Instant::now() + timeout;
+        self.read_complete_stream_with(buf, |buf, file, len| {
+            buf.fill_from(Deadline { file, deadline }, len)
+        })
+    }
+}
+
+/// Reader that waits for the file to become readable before every read, until the deadline.
+struct Deadline<'a, F> {
+    file: &'a mut F,
+    deadline: Instant,
+}
+
+impl<F> Read for Deadline<'_, F>
+where
+    F: Read + AsRawFd,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        poll_readable(self.file.as_raw_fd(), self.deadline)?;
+        self.file.read(buf)
+    }
+}
+
+/// Waits until `fd` is readable, has hung up or failed.
+fn poll_readable(fd: RawFd, deadline: Instant) -> io::Result<()> {
+    loop {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        // Round up, so that the deadline has passed once poll times out
+        let timeout = timeout.as_nanos().div_ceil(1_000_000);
+        let timeout = libc::c_int::try_from(timeout).unwrap_or(libc::c_int::MAX);
+
+        let mut pollfd = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        match unsafe { libc::poll(&mut pollfd, 1, timeout) } {
+            -1 => {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(err);
+                }
+            }
+            0 => {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "timed out waiting for the card",
+                ))
+            }
+            _ => return Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::message, C2hEncoder, StreamGeometry};
+    use std::{os::unix::net::UnixStream, thread};
+
+    const PACKET_SIZE: usize = 64;
+
+    fn geometry() -> StreamGeometry {
+        StreamGeometry::new(PACKET_SIZE, 64).unwrap()
+    }
+
+    /// Returns the card end of a socket pair and a stream reading the other end.
+    fn socket() -> (UnixStream, CardToHostStream<UnixStream>) {
+        let (card, host) = UnixStream::pair().unwrap();
+        let stream = CardToHostStream::with_geometry(host, 1024, geometry()).unwrap();
+        (card, stream)
+    }
+
+    fn beats(message: &[u8]) -> Vec<u8> {
+        let mut beats = Vec::new();
+        C2hEncoder::new(geometry())
+            .encode(message, &mut beats)
+            .unwrap();
+        beats
+    }
+
+    #[test]
+    fn timeout_without_data() {
+        let (_card, mut stream) = socket();
+        let start = Instant::now();
+        let err = stream
+            .next_stream_packet_timeout(Duration::from_millis(50))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn timeout_mid_message_resumes() {
+        let (mut card, mut stream) = socket();
+        let sent = message(3 * PACKET_SIZE);
+        let beats = beats(&sent);
+        card.write_all(&beats[..2 * PACKET_SIZE]).unwrap();
+
+        let mut received = Vec::new();
+        let err = stream
+            .read_complete_stream_timeout(&mut received, Duration::from_millis(50))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(received, sent[..PACKET_SIZE]);
+        assert!(!stream.is_poisoned());
+
+        card.write_all(&beats[2 * PACKET_SIZE..]).unwrap();
+        stream
+            .read_complete_stream_timeout(&mut received, Duration::from_millis(50))
+            .unwrap();
+        assert_eq!(received, sent);
+    }
+
+    /// The deadline covers all reads of a call, not every read on its own.
+    #[test]
+    fn deadline_spans_fills() {
+        let sent = message(6 * PACKET_SIZE);
+        let beats = beats(&sent);
+        let feed = |mut card: UnixStream, beats: Vec<u8>| {
+            thread::spawn(move || {
+                for beat in beats.chunks(PACKET_SIZE) {
+                    thread::sleep(Duration::from_millis(20));
+                    if card.write_all(beat).is_err() {
+                        break;
+                    }
+                }
+            })
+        };
+
+        let (card, mut stream) = socket();
+        let feeder = feed(card, beats.clone());
+        let err = stream
+            .read_complete_stream_timeout(Vec::new(), Duration::from_millis(70))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        drop(stream);
+        feeder.join().unwrap();
+
+        let (card, mut stream) = socket();
+        let feeder = feed(card, beats);
+        let mut received = Vec::new();
+        stream
+            .read_complete_stream_timeout(&mut received, Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(received, sent);
+        feeder.join().unwrap();
+    }
+}
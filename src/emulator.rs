@@ -0,0 +1,234 @@
+//! In-process software model of the card.
+//!
+//! [`loopback`] hands out a pair of endpoints that stand in for the H2C and C2H device files of a
+//! queue. Everything written to the [`H2cEndpoint`] is decoded like the card decodes the H2C
+//! packet-count protocol and every completed message is re-emitted on the [`C2hEndpoint`] using
+//! the C2H framing. This makes it possible to run a [`HostToCardStream`](crate::HostToCardStream)
+//! and a [`CardToHostStream`](crate::CardToHostStream) against each other entirely in memory.
+
+use crate::{C2hEncoder, H2cDecoder, Integrity, StreamGeometry};
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    sync::{Arc, Condvar, Mutex},
+};
+
+/// Configuration of the emulated card.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Config {
+    pub geometry: StreamGeometry,
+    /// With [`Integrity::Crc32c`], the card checks the trailer of every H2C message and appends
+    /// one to every C2H message.
+    pub integrity: Integrity,
+}
+
+/// Creates a connected pair of emulated queue endpoints.
+pub fn loopback() -> (H2cEndpoint, C2hEndpoint) {
+    loopback_with_config(Config::default())
+}
+
+/// Like [`loopback`], but for a card using the given packet size.
+pub fn loopback_with_geometry(geometry: StreamGeometry) -> (H2cEndpoint, C2hEndpoint) {
+    loopback_with_config(Config {
+        geometry,
+        ..Config::default()
+    })
+}
+
+/// Like [`loopback`], but for a card with the given configuration.
+pub fn loopback_with_config(config: Config) -> (H2cEndpoint, C2hEndpoint) {
+    // The data is looped back unchanged, so the trailer computed for the C2H message is the one
+    // that was received
+    let mut decoder = H2cDecoder::new(config.geometry);
+    decoder.set_integrity(config.integrity);
+    let mut encoder = C2hEncoder::new(config.geometry);
+    encoder.set_integrity(config.integrity);
+
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            c2h: VecDeque::new(),
+            h2c_closed: false,
+        }),
+        ready: Condvar::new(),
+    });
+
+    (
+        H2cEndpoint {
+            shared: shared.clone(),
+            packet_size: config.geometry.packet_size(),
+            decoder,
+            encoder,
+        },
+        C2hEndpoint { shared },
+    )
+}
+
+struct Shared {
+    state: Mutex<State>,
+    ready: Condvar,
+}
+
+struct State {
+    c2h: VecDeque<u8>,
+    h2c_closed: bool,
+}
+
+/// Emulated H2C device file.
+///
+/// Like the real queue, every call to [`write`](Write::write) is split into packets of at most
+/// the configured packet size. A message starts with a 4 byte packet holding the little-endian
+/// count of the packets that follow. If the count carries [`ESCAPE_FLAG`](crate::ESCAPE_FLAG),
+/// data beats of the message that start with the control sequence are escaped on the C2H side.
+///
+/// The packets are decoded with a [`H2cDecoder`], whose errors are returned by the write.
+pub struct H2cEndpoint {
+    shared: Arc<Shared>,
+    packet_size: usize,
+    decoder: H2cDecoder,
+    encoder: C2hEncoder,
+}
+
+impl Write for H2cEndpoint {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for packet in buf.chunks(self.packet_size) {
+            if let Some(message) = self.decoder.decode(packet)? {
+                let mut state = self.shared.state.lock().unwrap();
+                self.encoder.set_escape(message.escape);
+                self.encoder.encode(message.data, &mut state.c2h)?;
+                self.shared.ready.notify_all();
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for H2cEndpoint {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.h2c_closed = true;
+        self.shared.ready.notify_all();
+    }
+}
+
+/// Emulated C2H device file.
+///
+/// Reads block until the card has produced data. Once the [`H2cEndpoint`] is dropped and all
+/// data has been consumed, reads return end of file.
+pub struct C2hEndpoint {
+    shared: Arc<Shared>,
+}
+
+impl Read for C2hEndpoint {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.shared.state.lock().unwrap();
+        while state.c2h.is_empty() && !state.h2c_closed {
+            state = self.shared.ready.wait(state).unwrap();
+        }
+
+        let (front, back) = state.c2h.as_slices();
+        let count = usize::min(buf.len(), front.len() + back.len());
+        let from_front = usize::min(count, front.len());
+        buf[..from_front].copy_from_slice(&front[..from_front]);
+        buf[from_front..count].copy_from_slice(&back[..count - from_front]);
+        state.c2h.drain(..count);
+
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_util::{lengths, message, messages},
+        CardToHostStream, HostToCardStream,
+    };
+
+    fn round_trip(geometry: StreamGeometry) {
+        let (h2c, c2h) = loopback_with_geometry(geometry);
+        let capacity = 16 * geometry.packet_size();
+        let mut h2c = HostToCardStream::with_geometry(h2c, capacity, capacity, geometry).unwrap();
+        let mut c2h = CardToHostStream::with_geometry(c2h, capacity, geometry).unwrap();
+
+        for len in lengths(geometry.packet_size()) {
+            let sent = message(len);
+            h2c.write_remaining(&sent).unwrap();
+
+            let mut received = Vec::new();
+            assert_eq!(c2h.read_complete_stream(&mut received).unwrap(), len);
+            assert_eq!(received, sent, "message of {} bytes", len);
+        }
+    }
+
+    #[test]
+    fn round_trip_default_geometry() {
+        round_trip(StreamGeometry::default());
+    }
+
+    #[test]
+    fn round_trip_small_packets() {
+        round_trip(StreamGeometry::new(64, 64).unwrap());
+    }
+
+    #[test]
+    fn round_trip_with_integrity() {
+        let geometry = StreamGeometry::new(64, 64).unwrap();
+        let (h2c, c2h) = loopback_with_config(Config {
+            geometry,
+            integrity: Integrity::Crc32c,
+        });
+        let mut h2c = HostToCardStream::with_geometry(h2c, 1024, 1024, geometry).unwrap();
+        h2c.set_integrity(Integrity::Crc32c);
+        let mut c2h = CardToHostStream::with_geometry(c2h, 1024, geometry).unwrap();
+        c2h.set_integrity(Integrity::Crc32c);
+
+        for sent in messages(geometry.packet_size()) {
+            h2c.write_remaining(&sent).unwrap();
+
+            let mut received = Vec::new();
+            c2h.read_complete_stream(&mut received).unwrap();
+            assert_eq!(received, sent, "message of {} bytes", sent.len());
+        }
+    }
+
+    #[test]
+    fn round_trip_minimum_capacity_with_integrity() {
+        let geometry = StreamGeometry::default();
+        let (h2c, c2h) = loopback_with_config(Config {
+            geometry,
+            integrity: Integrity::Crc32c,
+        });
+        let beats = 3 * (geometry.packet_size() + crate::CTRL_SIZE);
+        let capacity = beats.next_multiple_of(geometry.align());
+        let mut h2c = HostToCardStream::new(h2c, capacity, capacity).unwrap();
+        h2c.set_integrity(Integrity::Crc32c);
+        let mut c2h = CardToHostStream::with_capacity(c2h, capacity).unwrap();
+        c2h.set_integrity(Integrity::Crc32c);
+
+        for len in [1, 8188, 8189, 8192, 20000] {
+            let sent = message(len);
+            h2c.write_remaining(&sent).unwrap();
+
+            let mut received = Vec::new();
+            c2h.read_complete_stream(&mut received).unwrap();
+            assert_eq!(received, sent, "message of {} bytes", len);
+        }
+    }
+
+    #[test]
+    fn capacity_below_three_beats_rejected() {
+        let (_, c2h) = loopback();
+        assert!(CardToHostStream::with_capacity(c2h, 12288).is_err());
+    }
+
+    #[test]
+    fn end_of_stream_after_h2c_dropped() {
+        let (h2c, mut c2h) = loopback();
+        drop(h2c);
+        assert_eq!(c2h.read(&mut [0; 16]).unwrap(), 0);
+    }
+}
@@ -0,0 +1,109 @@
+use crate::{ALIGN, CTRL_SIZE, PACKET_SIZE};
+use anyhow::{ensure, Result};
+
+/// Packet size and buffer alignment of a queue.
+///
+/// Has to match the AXI-stream packet size of the bitstream. Both stream types accept it at
+/// construction, the default is [`PACKET_SIZE`] and [`ALIGN`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct StreamGeometry {
+    packet_size: usize,
+    align: usize,
+}
+
+impl StreamGeometry {
+    /// `align` must be a power of two and `packet_size` must fit into the length field of a
+    /// control word.
+    pub fn new(packet_size: usize, align: usize) -> Result<Self> {
+        ensure!(align.is_power_of_two(), "alignment must be a power of two");
+        ensure!(packet_size >= CTRL_SIZE, "packet size too small");
+        ensure!(packet_size < 1 << 31, "packet size too large");
+
+        Ok(Self { packet_size, align })
+    }
+
+    pub fn packet_size(&self) -> usize {
+        self.packet_size
+    }
+
+    pub fn align(&self) -> usize {
+        self.align
+    }
+
+    /// Number of packets needed to transfer `len` bytes.
+    pub fn packet_count(&self, len: usize) -> usize {
+        usize::div_ceil(len, self.packet_size)
+    }
+
+    /// Smallest size that is a multiple of both the packet size and the alignment. Writes of
+    /// such blocks never end in the middle of a packet.
+    pub(crate) fn block_size(&self) -> usize {
+        let (mut a, mut b) = (self.packet_size, self.align);
+        while b != 0 {
+            (a, b) = (b, a % b);
+        }
+        self.packet_size / a * self.align
+    }
+}
+
+/// How payload packets that begin with [`CTRL_SEQ`] are protected.
+///
+/// A data beat starting with the control sequence is indistinguishable from a control beat unless
+/// the card escapes it with a zero control word. Both stream types of a queue should use the same
+/// mode.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum EscapeMode {
+    /// Every beat starting with the control sequence is taken as a control beat, as is.
+    #[default]
+    Disabled,
+    /// The host sets [`ESCAPE_FLAG`] in the packet count, asking the card to escape data beats
+    /// of the message. Control words are validated on the C2H side. Requires a bitstream that
+    /// honours the flag, like the [emulator](crate::emulator).
+    Escaped,
+    /// Nothing is escaped, but a message with a non-final packet starting with the control
+    /// sequence is rejected on the H2C side and control words are validated on the C2H side, so
+    /// that misframed data is reported as an error instead of being misparsed. The validation
+    /// catches any control word that does not fit the packet size, not every misframed beat.
+    Strict,
+}
+
+/// Bit of the H2C packet count that asks the card to escape the message.
+pub const ESCAPE_FLAG: u32 = 1 << 31;
+
+/// Integrity check carried by every message.
+///
+/// Both stream types of a queue and the bitstream have to agree on it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Integrity {
+    /// Messages carry no check.
+    #[default]
+    None,
+    /// Messages end with a little-endian CRC32C over the rest of the message. The trailer
+    /// counts towards the packets of the message, but is not part of the data returned.
+    Crc32c,
+}
+
+impl Integrity {
+    /// Size of the trailer in bytes.
+    pub fn trailer_len(&self) -> usize {
+        match self {
+            Self::None => 0,
+            Self::Crc32c => 4,
+        }
+    }
+}
+
+/// Size of the optional sequence number in front of every message.
+pub(crate) const SEQUENCE_LEN: usize = 4;
+
+/// Magic that starts every C2H control beat.
+pub const CTRL_SEQ: [u8; 4] = [0x5C, 0xF1, 0x37, 0x4A];
+
+impl Default for StreamGeometry {
+    fn default() -> Self {
+        Self {
+            packet_size: PACKET_SIZE,
+            align: ALIGN,
+        }
+    }
+}
@@ -0,0 +1,275 @@
+use super::{buf::Buf, framer::Framer, in_flight::InFlight, message::ZEROS, H2cError};
+use crate::{EscapeMode, Integrity, StreamGeometry};
+use anyhow::Result;
+use futures::Sink;
+use std::io::{self, Write};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// Asynchronous counterpart of [`HostToCardStream`](crate::HostToCardStream) for tokio.
+///
+/// Writes the same framing with the same options to any [`AsyncWrite`]. Every message is written
+/// completely and flushed before [`write_remaining`](Self::write_remaining) returns.
+///
+/// If a write to the file fails, or a future of the stream is dropped, in the middle of a
+/// message, the stream is poisoned like a
+/// [`HostToCardStream`](crate::HostToCardStream::is_poisoned).
+pub struct AsyncHostToCardStream<F> {
+    buf: Buf,
+    file: F,
+    framer: Framer,
+    in_flight: InFlight,
+}
+
+impl<F> AsyncHostToCardStream<F> {
+    /// Creates a stream that writes up to `capacity` bytes to `file` per call. `capacity` must be
+    /// a multiple of [`ALIGN`](crate::ALIGN) and hold at least one packet.
+    pub fn new(file: F, capacity: usize) -> Result<Self> {
+        Self::with_geometry(file, capacity, StreamGeometry::default())
+    }
+
+    /// Like [`new`](Self::new), but for a queue with the given packet size and alignment.
+    pub fn with_geometry(file: F, capacity: usize, geometry: StreamGeometry) -> Result<Self> {
+        anyhow::ensure!(capacity >= geometry.block_size(), "capacity too small");
+
+        Ok(Self {
+            buf: Buf::new(capacity, geometry)?,
+            file,
+            framer: Framer::new(geometry),
+            in_flight: InFlight::new(geometry),
+        })
+    }
+
+    pub fn geometry(&self) -> StreamGeometry {
+        self.framer.geometry
+    }
+
+    pub fn escape_mode(&self) -> EscapeMode {
+        self.framer.escape_mode
+    }
+
+    /// See [`HostToCardStream::set_escape_mode`](crate::HostToCardStream::set_escape_mode).
+    pub fn set_escape_mode(&mut self, mode: EscapeMode) {
+        self.framer.escape_mode = mode;
+    }
+
+    pub fn integrity(&self) -> Integrity {
+        self.framer.integrity
+    }
+
+    /// See [`HostToCardStream::set_integrity`](crate::HostToCardStream::set_integrity).
+    pub fn set_integrity(&mut self, integrity: Integrity) {
+        self.framer.integrity = integrity;
+    }
+
+    pub fn sequence_numbers(&self) -> bool {
+        self.framer.sequence_numbers
+    }
+
+    /// See [`HostToCardStream::set_sequence_numbers`](crate::HostToCardStream::set_sequence_numbers).
+    pub fn set_sequence_numbers(&mut self, enabled: bool) {
+        self.framer.sequence_numbers = enabled;
+    }
+
+    /// Sequence number the next message will carry.
+    pub fn next_sequence(&self) -> u32 {
+        self.framer.next_sequence
+    }
+
+    /// See [`HostToCardStream::is_poisoned`](crate::HostToCardStream::is_poisoned).
+    pub fn is_poisoned(&self) -> bool {
+        self.in_flight.poisoned()
+    }
+
+    /// See [`HostToCardStream::reset`](crate::HostToCardStream::reset).
+    pub fn reset(&mut self) {
+        self.in_flight.reset();
+        self.buf.clear();
+    }
+}
+
+impl<F> AsyncHostToCardStream<F>
+where
+    F: AsyncWrite + Unpin,
+{
+    /// Writes `remaining` as one message.
+    ///
+    /// Fails with [`H2cError::EmptyMessage`] before anything is written if `remaining` is empty
+    /// or, in [`EscapeMode::Strict`], with [`H2cError::UnescapedCtrlSeq`] if a packet other than
+    /// the last one starts with [`CTRL_SEQ`](crate::CTRL_SEQ).
+    pub async fn write_remaining(&mut self, remaining: &[u8]) -> io::Result<()> {
+        if remaining.is_empty() {
+            return Err(H2cError::EmptyMessage.into());
+        }
+        self.framer.check(&[remaining])?;
+
+        let count = self.framer.message_count(remaining.len())?;
+        self.in_flight.begin()?;
+        self.write_count(count).await?;
+
+        let header = self.framer.next_header();
+        self.push(header.as_slice()).await?;
+        self.push(remaining).await?;
+        if let Some(trailer) = self
+            .framer
+            .trailer(|| crc32c::crc32c_append(header.crc(), remaining))
+        {
+            self.push(&trailer).await?;
+        }
+        self.write_out().await?;
+        self.in_flight.end();
+
+        Ok(())
+    }
+
+    /// See [`HostToCardStream::write_remaining_packet_count`](crate::HostToCardStream::write_remaining_packet_count).
+    pub async fn write_remaining_packet_count(&mut self, count: u32) -> io::Result<()> {
+        let count = self.framer.count(count)?;
+        self.in_flight.begin()?;
+        self.write_count(count).await?;
+        self.in_flight.end();
+
+        Ok(())
+    }
+
+    /// Writes the buffered data, a partially filled packet as a short packet.
+    pub async fn flush(&mut self) -> io::Result<()> {
+        self.in_flight.begin()?;
+        self.write_out().await?;
+        self.in_flight.end();
+
+        Ok(())
+    }
+
+    /// See [`HostToCardStream::pad_message`](crate::HostToCardStream::pad_message).
+    pub async fn pad_message(&mut self) -> io::Result<()> {
+        let Some(remaining) = self.in_flight.remaining() else {
+            return Err(H2cError::Poisoned { remaining: None }.into());
+        };
+        if self.in_flight.poisoned() {
+            self.in_flight.recover();
+            self.buf.clear();
+        }
+
+        self.in_flight.begin()?;
+        let len = remaining as usize * self.framer.geometry.packet_size();
+        let mut rest = len.saturating_sub(self.buf.len());
+        while rest > 0 {
+            let len = usize::min(rest, ZEROS.len());
+            self.push(&ZEROS[..len]).await?;
+            rest -= len;
+        }
+        self.write_out().await?;
+        self.in_flight.end();
+
+        Ok(())
+    }
+
+    /// Returns a [`Sink`] that writes every item as one message. Pin it, e.g. with
+    /// [`pin!`](std::pin::pin), to use the `SinkExt` methods.
+    pub fn messages<'a, B>(&'a mut self) -> impl Sink<B, Error = io::Error> + 'a
+    where
+        B: AsRef<[u8]> + 'a,
+    {
+        futures::sink::unfold(self, |this, message: B| async move {
+            this.write_remaining(message.as_ref()).await?;
+            Ok(this)
+        })
+    }
+
+    async fn write_count(&mut self, count: [u8; 4]) -> io::Result<()> {
+        self.write_out().await?;
+        self.buf.write_all(&count)?;
+        let result = self.write_out().await;
+        self.in_flight.count_written(count, &mut self.buf, result)
+    }
+
+    async fn write_out(&mut self) -> io::Result<()> {
+        let (aligned, rest) = self.buf.data().split_at(self.buf.aligned_len());
+        for data in [aligned, rest] {
+            write_tracked(&mut self.file, &mut self.in_flight, data).await?;
+        }
+        self.buf.consume(self.buf.len());
+
+        self.file
+            .flush()
+            .await
+            .map_err(|err| self.in_flight.fail(err))
+    }
+
+    /// Buffers `data`, writing whole packets whenever the buffer is full.
+    async fn push(&mut self, mut data: &[u8]) -> io::Result<()> {
+        loop {
+            let count = self.buf.write(data)?;
+            data = &data[count..];
+            if data.is_empty() {
+                break Ok(());
+            }
+
+            let len = self.buf.aligned_len();
+            let data = &self.buf.data()[..len];
+            write_tracked(&mut self.file, &mut self.in_flight, data).await?;
+            self.buf.consume(len);
+        }
+    }
+}
+
+/// Writes all of `data` to `file`, recording the writes in `in_flight`.
+async fn write_tracked<F>(file: &mut F, in_flight: &mut InFlight, mut data: &[u8]) -> io::Result<()>
+where
+    F: AsyncWrite + Unpin,
+{
+    while !data.is_empty() {
+        let len = match file.write(data).await {
+            Ok(0) => Err(io::ErrorKind::WriteZero.into()),
+            Ok(len) => Ok(len),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => Err(err),
+        }
+        .map_err(|err| in_flight.fail(err))?;
+        in_flight.written(len);
+        data = &data[len..];
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::messages, HostToCardStream};
+    use futures::SinkExt;
+    use tokio::io::AsyncReadExt;
+
+    /// The async stream writes the same bytes as the synchronous one, which the emulator tests
+    /// check against the card.
+    #[tokio::test]
+    async fn messages_sink_through_duplex() {
+        let geometry = StreamGeometry::new(64, 64).unwrap();
+        let sent = messages(geometry.packet_size());
+
+        let (file, mut card) = tokio::io::duplex(100);
+        let reader = tokio::spawn(async move {
+            let mut received = Vec::new();
+            card.read_to_end(&mut received).await.unwrap();
+            received
+        });
+        let mut stream = AsyncHostToCardStream::with_geometry(file, 256, geometry).unwrap();
+        stream.set_integrity(Integrity::Crc32c);
+        stream.set_sequence_numbers(true);
+        {
+            let mut messages = std::pin::pin!(stream.messages());
+            for message in &sent {
+                messages.send(message).await.unwrap();
+            }
+        }
+        drop(stream);
+        let received = reader.await.unwrap();
+
+        let mut expected = HostToCardStream::with_geometry(Vec::new(), 256, 256, geometry).unwrap();
+        expected.set_integrity(Integrity::Crc32c);
+        expected.set_sequence_numbers(true);
+        for message in &sent {
+            expected.write_remaining(message).unwrap();
+        }
+        assert_eq!(received, expected.finish().unwrap());
+    }
+}
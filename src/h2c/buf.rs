@@ -1,10 +1,10 @@
 use crate::{
     util::{mem_aligned, mem_aligned_free},
-    ALIGN,
+    StreamGeometry,
 };
 use anyhow::{ensure, Result};
 use std::{
-    io::{self, Write},
+    io::{self, Read, Write},
     ptr::{self, NonNull},
 };
 
@@ -13,16 +13,23 @@ unsafe impl Send for Buf {}
 pub struct Buf {
     ptr: NonNull<u8>,
     capacity: usize,
+    align: usize,
+    block_size: usize,
     len: usize,
 }
 
 impl Buf {
-    pub fn new(capacity: usize) -> Result<Self> {
-        ensure!(capacity % ALIGN == 0);
-        let ptr = mem_aligned(capacity, ALIGN)?;
+    pub fn new(capacity: usize, geometry: StreamGeometry) -> Result<Self> {
+        let align = geometry.align();
+        ensure!(capacity % align == 0);
+        let ptr = mem_aligned(capacity, align)?;
+        // Zeroed, so that readers may fill the spare capacity
+        unsafe { ptr.as_ptr().write_bytes(0, capacity) };
         Ok(Self {
             ptr,
             capacity,
+            align,
+            block_size: geometry.block_size(),
             len: 0,
         })
     }
@@ -31,28 +38,101 @@ impl Buf {
         self.len
     }
 
-    pub fn write_into<W: Write>(&mut self, mut writer: W) -> io::Result<()> {
-        if self.len == 0 {
-            return Ok(());
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn data(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+
+    /// Length of the part of the buffer made of whole packets.
+    pub fn aligned_len(&self) -> usize {
+        self.len / self.block_size * self.block_size
+    }
+
+    /// Shortens the buffer to its first `len` bytes.
+    pub fn truncate(&mut self, len: usize) {
+        self.len = usize::min(self.len, len);
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    /// Removes the first `count` bytes and moves the rest of the buffer to the front.
+    pub fn consume(&mut self, count: usize) {
+        debug_assert!(count <= self.len);
+        unsafe {
+            ptr::copy(
+                self.ptr.as_ptr().add(count),
+                self.ptr.as_ptr(),
+                self.len - count,
+            );
         }
+        self.len -= count;
+    }
 
-        // Write aligned part of the buffer
-        let len = self.len / ALIGN * ALIGN;
-        let slice = unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), len) };
-        writer.write_all(slice)?;
+    /// Appends up to `len` bytes read from `reader` directly into the buffer. Reads until `len`
+    /// bytes were read or the reader is at its end, returns the number of bytes read. On error,
+    /// the bytes read by this call are dropped.
+    pub fn read_from<R: Read>(&mut self, mut reader: R, len: usize) -> io::Result<usize> {
+        debug_assert!(len <= self.capacity - self.len);
+        let start = self.len;
+        let spare = unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr().add(start), len) };
+        let mut read = 0;
+        while read < len {
+            match reader.read(&mut spare[read..]) {
+                Ok(0) => break,
+                Ok(count) => read += count,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        self.len += read;
+        Ok(read)
+    }
 
-        // Write rest of the buffer
-        if len < self.len {
-            let slice =
-                unsafe { std::slice::from_raw_parts(self.ptr.as_ptr().add(len), self.len - len) };
-            writer.write_all(slice)?;
+    /// Writes the whole packets of the buffer and then the rest. The written bytes are removed
+    /// from the buffer as they go, so that on error only what was not written is left.
+    pub fn write_into<W: Write>(&mut self, mut writer: W) -> io::Result<()> {
+        let mut aligned = self.aligned_len();
+        while aligned > 0 {
+            aligned -= self.write_front(&mut writer, aligned)?;
+        }
+        while self.len > 0 {
+            self.write_front(&mut writer, self.len)?;
         }
 
-        // Reset buffer
-        self.len = 0;
+        Ok(())
+    }
+
+    /// Writes only the whole packets of the buffer and keeps the rest, so that a partially filled
+    /// packet is not sent as a short packet in the middle of a message.
+    pub fn write_aligned_into<W: Write>(&mut self, mut writer: W) -> io::Result<()> {
+        let mut aligned = self.aligned_len();
+        while aligned > 0 {
+            aligned -= self.write_front(&mut writer, aligned)?;
+        }
 
         Ok(())
     }
+
+    /// Writes up to the first `len` bytes of the buffer with one successful call to `writer` and
+    /// consumes them, returns the number of bytes written.
+    fn write_front<W: Write>(&mut self, writer: &mut W, len: usize) -> io::Result<usize> {
+        loop {
+            match writer.write(&self.data()[..len]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(count) => {
+                    self.consume(count);
+                    return Ok(count);
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
 }
 
 impl Write for Buf {
@@ -75,7 +155,7 @@ impl Write for Buf {
 impl Drop for Buf {
     fn drop(&mut self) {
         unsafe {
-            mem_aligned_free(self.ptr.as_ptr(), self.capacity, ALIGN);
+            mem_aligned_free(self.ptr.as_ptr(), self.capacity, self.align);
         }
     }
 }
@@ -0,0 +1,530 @@
+use super::H2cError;
+use crate::{framing::SEQUENCE_LEN, Integrity, StreamGeometry, ESCAPE_FLAG};
+use std::ops::Range;
+
+/// Decoder of the H2C packet-count protocol that works on packets and does no I/O itself.
+///
+/// A message starts with a 4 byte packet holding the little-endian count of the packets that
+/// follow, optionally with [`ESCAPE_FLAG`], and all of its packets but the last one are full.
+/// Like on the card, packets are delimited by the writes to the device: every write is split into
+/// packets of at most the packet size, which are passed to [`decode`](Self::decode) one by one. A
+/// capture of an H2C stream therefore has to keep the boundaries of the writes, like the
+/// [emulator](crate::emulator) sees them. At the end of a capture, [`finish`](Self::finish)
+/// reports a message that is missing packets.
+#[derive(Debug)]
+pub struct H2cDecoder {
+    packet_size: usize,
+    integrity: Integrity,
+    sequence_numbers: bool,
+    remaining_packets: Option<u32>,
+    escape: bool,
+    offset: u64,
+    message: u64,
+    data: Vec<u8>,
+    last_sequence: Option<u32>,
+    expected_sequence: Option<u32>,
+}
+
+/// Message decoded by [`H2cDecoder::decode`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct H2cMessage<'a> {
+    /// Data of the message, without sequence number and trailer if the decoder expects them.
+    pub data: &'a [u8],
+    /// Whether the packet count carried [`ESCAPE_FLAG`].
+    pub escape: bool,
+    /// Sequence number of the message, if the decoder expects them.
+    pub sequence: Option<u32>,
+}
+
+impl H2cDecoder {
+    pub fn new(geometry: StreamGeometry) -> Self {
+        Self {
+            packet_size: geometry.packet_size(),
+            integrity: Integrity::None,
+            sequence_numbers: false,
+            remaining_packets: None,
+            escape: false,
+            offset: 0,
+            message: 0,
+            data: Vec::new(),
+            last_sequence: None,
+            expected_sequence: None,
+        }
+    }
+
+    pub fn integrity(&self) -> Integrity {
+        self.integrity
+    }
+
+    /// Sets the integrity check expected at the end of every message. With
+    /// [`Integrity::Crc32c`], the trailer is checked and removed from the decoded data.
+    pub fn set_integrity(&mut self, integrity: Integrity) {
+        self.integrity = integrity;
+    }
+
+    pub fn sequence_numbers(&self) -> bool {
+        self.sequence_numbers
+    }
+
+    /// Expects a sequence number in front of every message, like
+    /// [`HostToCardStream::set_sequence_numbers`](crate::HostToCardStream::set_sequence_numbers)
+    /// sends. The number is checked and removed from the decoded data.
+    pub fn set_sequence_numbers(&mut self, enabled: bool) {
+        self.sequence_numbers = enabled;
+    }
+
+    /// Sequence number of the last decoded message.
+    pub fn last_sequence(&self) -> Option<u32> {
+        self.last_sequence
+    }
+
+    /// Packets still outstanding for the current message, `None` while a packet count is
+    /// expected.
+    pub fn remaining_packets(&self) -> Option<u32> {
+        self.remaining_packets
+    }
+
+    /// Stream offset of the next packet.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Decodes the next packet, returns the message it completes.
+    ///
+    /// Packet counts of zero are ignored, like the card does. An empty `packet` is no packet and
+    /// is ignored as well. A `packet` longer than the packet size fails with
+    /// [`H2cError::LongPacket`].
+    pub fn decode(&mut self, packet: &[u8]) -> Result<Option<H2cMessage<'_>>, H2cError> {
+        if packet.is_empty() {
+            return Ok(None);
+        }
+
+        let offset = self.offset;
+        self.offset += packet.len() as u64;
+
+        if packet.len() > self.packet_size {
+            if self.remaining_packets.take().is_some() {
+                self.message += 1;
+            }
+            return Err(H2cError::LongPacket {
+                len: packet.len(),
+                packet_size: self.packet_size,
+                offset,
+            });
+        }
+
+        let Some(remaining) = self.remaining_packets else {
+            let count: [u8; 4] = packet.try_into().map_err(|_| H2cError::InvalidCount {
+                len: packet.len(),
+                offset,
+            })?;
+            let count = u32::from_le_bytes(count);
+            self.escape = count & ESCAPE_FLAG != 0;
+            let count = count & !ESCAPE_FLAG;
+            if count != 0 {
+                self.remaining_packets = Some(count);
+                self.data.clear();
+            }
+            return Ok(None);
+        };
+
+        if remaining > 1 && packet.len() < self.packet_size {
+            self.remaining_packets = None;
+            self.message += 1;
+            return Err(H2cError::ShortPacket {
+                len: packet.len(),
+                remaining,
+                offset,
+                message: self.message - 1,
+            });
+        }
+
+        self.data.extend_from_slice(packet);
+        if remaining > 1 {
+            self.remaining_packets = Some(remaining - 1);
+            return Ok(None);
+        }
+
+        self.remaining_packets = None;
+        self.message += 1;
+        let (range, sequence) = self.check_message(offset, self.message - 1)?;
+        Ok(Some(H2cMessage {
+            data: &self.data[range],
+            escape: self.escape,
+            sequence,
+        }))
+    }
+
+    /// Checks that the stream did not end in the middle of a message.
+    pub fn finish(&self) -> Result<(), H2cError> {
+        match self.remaining_packets {
+            Some(remaining) => Err(H2cError::Truncated {
+                remaining,
+                message: self.message,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Drops the message being decoded, the next packet is expected to be a packet count.
+    pub fn reset(&mut self) {
+        self.remaining_packets = None;
+        self.data.clear();
+    }
+
+    /// Checks the sequence number and the trailer of the complete message, returns the range of
+    /// its data and its sequence number.
+    fn check_message(
+        &mut self,
+        offset: u64,
+        message: u64,
+    ) -> Result<(Range<usize>, Option<u32>), H2cError> {
+        let mut end = self.data.len();
+        if self.integrity == Integrity::Crc32c {
+            let Some(len) = end.checked_sub(4) else {
+                return Err(H2cError::MissingTrailer { offset, message });
+            };
+            let expected = u32::from_le_bytes(self.data[len..].try_into().unwrap());
+            let actual = crc32c::crc32c(&self.data[..len]);
+            if expected != actual {
+                return Err(H2cError::CrcMismatch {
+                    expected,
+                    actual,
+                    offset,
+                    message,
+                });
+            }
+            end = len;
+        }
+
+        if !self.sequence_numbers {
+            return Ok((0..end, None));
+        }
+        let Some(header) = self.data[..end].first_chunk::<SEQUENCE_LEN>() else {
+            return Err(H2cError::MissingHeader { offset, message });
+        };
+        let found = u32::from_le_bytes(*header);
+        let expected = self.expected_sequence;
+        self.last_sequence = Some(found);
+        self.expected_sequence = Some(found.wrapping_add(1));
+        match expected {
+            Some(expected) if expected != found => Err(H2cError::SequenceMismatch {
+                expected,
+                found,
+                offset,
+                message,
+            }),
+            _ => Ok((SEQUENCE_LEN..end, Some(found))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_util::{self, message, messages, Capture},
+        EscapeMode, HostToCardStream,
+    };
+
+    fn geometry() -> StreamGeometry {
+        StreamGeometry::new(64, 64).unwrap()
+    }
+
+    fn decoder() -> H2cDecoder {
+        H2cDecoder::new(geometry())
+    }
+
+    /// The writes of a [`HostToCardStream`] sending `messages`, with `setup` applied first.
+    fn capture(
+        messages: &[Vec<u8>],
+        setup: impl FnOnce(&mut HostToCardStream<Capture>),
+    ) -> Vec<Vec<u8>> {
+        let mut stream =
+            HostToCardStream::with_geometry(Capture::default(), 1024, 1024, geometry()).unwrap();
+        setup(&mut stream);
+        for message in messages {
+            stream.write_remaining(message).unwrap();
+        }
+        stream.finish().unwrap().writes
+    }
+
+    fn packets(writes: &[Vec<u8>]) -> Vec<&[u8]> {
+        test_util::packets(writes, geometry().packet_size())
+    }
+
+    /// Decodes `packets` up to the first error, returns the decoded messages and the error.
+    fn decode_all(decoder: &mut H2cDecoder, packets: &[&[u8]]) -> (Vec<Vec<u8>>, Option<H2cError>) {
+        let mut decoded = Vec::new();
+        for packet in packets {
+            match decoder.decode(packet) {
+                Ok(Some(message)) => decoded.push(message.data.to_vec()),
+                Ok(None) => {}
+                Err(err) => return (decoded, Some(err)),
+            }
+        }
+        (decoded, None)
+    }
+
+    /// Offsets at which the messages of `writes` end.
+    fn message_ends(writes: &[Vec<u8>], setup: impl FnOnce(&mut H2cDecoder)) -> Vec<usize> {
+        let mut decoder = decoder();
+        setup(&mut decoder);
+        let mut ends = Vec::new();
+        for packet in packets(writes) {
+            if decoder.decode(packet).unwrap().is_some() {
+                ends.push(decoder.offset() as usize);
+            }
+        }
+        ends
+    }
+
+    /// Splits the write holding the byte at `split` in two, like a capture that lost a write
+    /// boundary would.
+    fn split_writes(writes: &[Vec<u8>], split: usize) -> Vec<Vec<u8>> {
+        let mut split_writes = Vec::new();
+        let mut start = 0;
+        for write in writes {
+            let end = start + write.len();
+            match start < split && split < end {
+                true => {
+                    split_writes.push(write[..split - start].to_vec());
+                    split_writes.push(write[split - start..].to_vec());
+                }
+                false => split_writes.push(write.clone()),
+            }
+            start = end;
+        }
+        split_writes
+    }
+
+    /// A write boundary within a packet is never decoded as a valid message: the messages
+    /// ending before it are decoded, then an error is reported.
+    #[test]
+    fn every_split() {
+        let sent = messages(geometry().packet_size());
+        let writes = capture(&sent, |stream| {
+            stream.set_integrity(Integrity::Crc32c);
+            stream.set_sequence_numbers(true);
+        });
+        let setup = |decoder: &mut H2cDecoder| {
+            decoder.set_integrity(Integrity::Crc32c);
+            decoder.set_sequence_numbers(true);
+        };
+        let ends = message_ends(&writes, setup);
+        let boundaries = packets(&writes)
+            .iter()
+            .scan(0, |offset, packet| {
+                *offset += packet.len();
+                Some(*offset)
+            })
+            .collect::<Vec<_>>();
+
+        for split in 1..*ends.last().unwrap() {
+            let writes = split_writes(&writes, split);
+            let mut decoder = decoder();
+            setup(&mut decoder);
+            let (decoded, err) = decode_all(&mut decoder, &packets(&writes));
+            if boundaries.contains(&split) {
+                assert_eq!((&decoded, err), (&sent, None), "split at {}", split);
+            } else {
+                assert!(err.is_some(), "split at {}", split);
+                let complete = ends.iter().filter(|&&end| end <= split).count();
+                assert_eq!(decoded, sent[..complete], "split at {}", split);
+            }
+        }
+    }
+
+    #[test]
+    fn short_packet_rejected() {
+        let mut decoder = decoder();
+        for len in 1..64 {
+            assert_eq!(decoder.decode(&u32::to_le_bytes(3)), Ok(None));
+            assert_eq!(decoder.decode(&[1; 64]), Ok(None));
+            let offset = decoder.offset();
+            assert_eq!(
+                decoder.decode(&vec![2; len]),
+                Err(H2cError::ShortPacket {
+                    len,
+                    remaining: 2,
+                    offset,
+                    message: len as u64 - 1,
+                })
+            );
+            // The message is dropped, a packet count is expected next
+            assert_eq!(decoder.remaining_packets(), None);
+        }
+        assert_eq!(decoder.decode(&u32::to_le_bytes(1)), Ok(None));
+        let message = decoder.decode(&[3; 10]).unwrap().unwrap();
+        assert_eq!(message.data, &[3; 10]);
+    }
+
+    #[test]
+    fn truncated_stream() {
+        let sent = [message(200), message(10)];
+        let writes = capture(&sent, |_| {});
+        let packets = packets(&writes);
+        // Packet count and four packets, then the next message
+        assert_eq!(packets.len(), 7);
+        for len in 0..packets.len() {
+            let mut decoder = decoder();
+            decode_all(&mut decoder, &packets[..len]);
+            let expected = match len {
+                1..=4 => Err(H2cError::Truncated {
+                    remaining: 5 - len as u32,
+                    message: 0,
+                }),
+                6 => Err(H2cError::Truncated {
+                    remaining: 1,
+                    message: 1,
+                }),
+                _ => Ok(()),
+            };
+            assert_eq!(decoder.finish(), expected, "{} packets", len);
+        }
+    }
+
+    #[test]
+    fn invalid_count_rejected() {
+        let mut decoder = decoder();
+        for len in (1..=64).filter(|&len| len != 4) {
+            let offset = decoder.offset();
+            assert_eq!(
+                decoder.decode(&vec![1; len]),
+                Err(H2cError::InvalidCount { len, offset })
+            );
+            assert_eq!(decoder.remaining_packets(), None);
+        }
+        assert_eq!(decoder.decode(&u32::to_le_bytes(1)), Ok(None));
+        assert!(decoder.decode(&[3; 10]).unwrap().is_some());
+    }
+
+    /// The flag is reported with the message and is not part of the count.
+    #[test]
+    fn escape_flag_in_each_mode() {
+        let sent = messages(geometry().packet_size());
+        for mode in [
+            EscapeMode::Disabled,
+            EscapeMode::Escaped,
+            EscapeMode::Strict,
+        ] {
+            let writes = capture(&sent, |stream| stream.set_escape_mode(mode));
+            let mut decoder = decoder();
+            let mut decoded = Vec::new();
+            for packet in packets(&writes) {
+                if let Some(message) = decoder.decode(packet).unwrap() {
+                    assert_eq!(message.escape, mode == EscapeMode::Escaped, "{:?}", mode);
+                    decoded.push(message.data.to_vec());
+                }
+            }
+            assert_eq!(decoded, sent, "{:?}", mode);
+        }
+
+        // A count of zero is ignored, with or without the flag
+        let mut decoder = decoder();
+        assert_eq!(decoder.decode(&u32::to_le_bytes(ESCAPE_FLAG)), Ok(None));
+        assert_eq!(decoder.remaining_packets(), None);
+        assert_eq!(decoder.decode(&u32::to_le_bytes(ESCAPE_FLAG | 1)), Ok(None));
+        assert_eq!(decoder.remaining_packets(), Some(1));
+    }
+
+    #[test]
+    fn crc_mismatch() {
+        let sent = [message(100), message(10)];
+        let writes = capture(&sent, |stream| stream.set_integrity(Integrity::Crc32c));
+        let packets = packets(&writes);
+        // Packet count, then two packets of data and trailer
+        assert_eq!(packets[1].len() + packets[2].len(), 104);
+
+        for pos in 0..104 {
+            let mut corrupted = packets[..3].concat();
+            corrupted[4 + pos] ^= 0xff;
+            let (count, data) = corrupted.split_at(4);
+            let (first, last) = data.split_at(64);
+            let mut decoder = decoder();
+            decoder.set_integrity(Integrity::Crc32c);
+            let (decoded, err) = decode_all(&mut decoder, &[count, first, last]);
+            assert!(decoded.is_empty());
+            assert!(
+                matches!(
+                    err,
+                    Some(H2cError::CrcMismatch {
+                        offset: 68,
+                        message: 0,
+                        ..
+                    })
+                ),
+                "byte {}: {:?}",
+                pos,
+                err
+            );
+
+            // The next message is decoded
+            let (decoded, err) = decode_all(&mut decoder, &packets[3..]);
+            assert_eq!((decoded, err), (vec![message(10)], None));
+        }
+    }
+
+    #[test]
+    fn sequence_mismatch() {
+        let sent = [message(10), message(100), message(20), message(30)];
+        let writes = capture(&sent, |stream| stream.set_sequence_numbers(true));
+        let packets = packets(&writes);
+        // Packet count and data of every message, the second one spans two packets
+        assert_eq!(packets.len(), 9);
+        let mut decoder = decoder();
+        decoder.set_sequence_numbers(true);
+
+        // The second message is lost
+        let (decoded, err) = decode_all(&mut decoder, &packets[..2]);
+        assert_eq!((decoded, err), (vec![message(10)], None));
+        let offset = decoder.offset() + 4;
+        let (decoded, err) = decode_all(&mut decoder, &packets[5..7]);
+        assert!(decoded.is_empty());
+        assert_eq!(
+            err,
+            Some(H2cError::SequenceMismatch {
+                expected: 1,
+                found: 2,
+                offset,
+                message: 1,
+            })
+        );
+        assert_eq!(decoder.last_sequence(), Some(2));
+
+        // The check continues from the received number
+        let (decoded, err) = decode_all(&mut decoder, &packets[7..]);
+        assert_eq!((decoded, err), (vec![message(30)], None));
+        assert_eq!(decoder.last_sequence(), Some(3));
+    }
+
+    #[test]
+    fn long_packet_rejected() {
+        let mut decoder = decoder();
+        assert_eq!(
+            decoder.decode(&[0; 65]),
+            Err(H2cError::LongPacket {
+                len: 65,
+                packet_size: 64,
+                offset: 0,
+            })
+        );
+        assert_eq!(decoder.offset(), 65);
+        assert_eq!(decoder.decode(&u32::to_le_bytes(2)), Ok(None));
+        assert_eq!(decoder.decode(&[1; 64]), Ok(None));
+        assert_eq!(
+            decoder.decode(&[2; 128]),
+            Err(H2cError::LongPacket {
+                len: 128,
+                packet_size: 64,
+                offset: 133,
+            })
+        );
+
+        // The message is dropped, a packet count is expected next
+        assert_eq!(decoder.remaining_packets(), None);
+        assert_eq!(decoder.decode(&u32::to_le_bytes(1)), Ok(None));
+        let message = decoder.decode(&[3; 10]).unwrap().unwrap();
+        assert_eq!(message.data, &[3; 10]);
+    }
+}
@@ -0,0 +1,266 @@
+use std::{error::Error, fmt, io};
+
+/// Errors of the H2C side.
+///
+/// Misuse of a [`HostToCardStream`](super::HostToCardStream) is returned wrapped in an
+/// [`io::Error`] of kind [`InvalidInput`](io::ErrorKind::InvalidInput). Errors reported by
+/// [`H2cDecoder`](super::H2cDecoder) while decoding the H2C framing are wrapped with kind
+/// [`InvalidData`](io::ErrorKind::InvalidData) where an I/O error is expected. After such an
+/// error, the message being decoded is dropped and the decoder expects a packet count next.
+/// [`Poisoned`](Self::Poisoned) is wrapped with kind [`Other`](io::ErrorKind::Other).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum H2cError {
+    /// A message without data was started. The card cannot receive it, because a packet count of
+    /// zero is ignored.
+    EmptyMessage,
+    /// More data was written to a [`MessageWriter`](super::MessageWriter) than the length it
+    /// was started with.
+    MessageTooLong {
+        /// Length of the message.
+        len: usize,
+    },
+    /// A [`MessageWriter`](super::MessageWriter) was finished before all data of the message was
+    /// written. The rest of the message was filled with zeros.
+    MessageTooShort {
+        /// Length of the message.
+        len: usize,
+        /// Bytes written before the message was finished.
+        written: usize,
+    },
+    /// The source of a message ended before the announced length.
+    /// [`write_complete_stream`](super::HostToCardStream::write_complete_stream) filled the rest
+    /// of the message, including the trailer, with zeros.
+    ShortSource {
+        /// Announced length of the message.
+        length: usize,
+        /// Bytes read from the source.
+        read: usize,
+    },
+    /// The source of a message holds more data than the announced length. The message was sent
+    /// with the announced length, data read from the source past it was dropped.
+    LongSource {
+        /// Announced length of the message.
+        length: usize,
+    },
+    /// The packet count does not fit in the count word next to
+    /// [`ESCAPE_FLAG`](crate::ESCAPE_FLAG).
+    PacketCountTooLarge {
+        /// Number of packets.
+        count: usize,
+    },
+    /// In [`EscapeMode::Strict`](crate::EscapeMode::Strict), a packet other than the last one of
+    /// the message starts with [`CTRL_SEQ`](crate::CTRL_SEQ).
+    UnescapedCtrlSeq {
+        /// Index of the packet in the message.
+        packet: usize,
+    },
+    /// A write to the device failed in the middle of a message, so the card still waits for
+    /// packets of it. The stream refuses all traffic until it is recovered with
+    /// [`pad_message`](super::HostToCardStream::pad_message) or
+    /// [`reset`](super::HostToCardStream::reset).
+    Poisoned {
+        /// Packets the card still waits for, `None` if unknown.
+        remaining: Option<u32>,
+    },
+    /// A packet that is not 4 bytes long arrived where a packet count was expected.
+    InvalidCount {
+        /// Length of the packet.
+        len: usize,
+        /// Byte offset of the packet in the stream.
+        offset: u64,
+    },
+    /// A packet longer than the packet size was passed to the decoder, the write it came from
+    /// was not split into packets. A message being decoded is dropped.
+    LongPacket {
+        /// Length of the packet.
+        len: usize,
+        /// Packet size of the decoder.
+        packet_size: usize,
+        /// Byte offset of the packet in the stream.
+        offset: u64,
+    },
+    /// A packet other than the last one of a message is shorter than the packet size, so the
+    /// packet count does not match the payload.
+    ShortPacket {
+        /// Length of the packet.
+        len: usize,
+        /// Packets the count announced that were still outstanding, including this one.
+        remaining: u32,
+        /// Byte offset of the packet in the stream.
+        offset: u64,
+        /// Index of the message.
+        message: u64,
+    },
+    /// The stream ended before all packets announced by the count arrived.
+    Truncated {
+        /// Packets the count announced that are missing.
+        remaining: u32,
+        /// Index of the message.
+        message: u64,
+    },
+    /// The CRC32C trailer does not match the data of the message.
+    CrcMismatch {
+        /// Checksum carried by the trailer.
+        expected: u32,
+        /// Checksum computed over the received data.
+        actual: u32,
+        /// Byte offset of the last packet of the message.
+        offset: u64,
+        /// Index of the message.
+        message: u64,
+    },
+    /// The message is too short to hold the trailer required by the
+    /// [`Integrity`](crate::Integrity) mode.
+    MissingTrailer {
+        /// Byte offset of the last packet of the message.
+        offset: u64,
+        /// Index of the message.
+        message: u64,
+    },
+    /// The message is too short to hold the sequence number.
+    MissingHeader {
+        /// Byte offset of the last packet of the message.
+        offset: u64,
+        /// Index of the message.
+        message: u64,
+    },
+    /// The sequence number of a message is not the successor of the previous one. The sequence
+    /// check continues from its number.
+    SequenceMismatch {
+        /// Sequence number following the one of the previous message.
+        expected: u32,
+        /// Sequence number carried by the message.
+        found: u32,
+        /// Byte offset of the last packet of the message.
+        offset: u64,
+        /// Index of the message.
+        message: u64,
+    },
+}
+
+impl fmt::Display for H2cError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::EmptyMessage => write!(f, "message is empty"),
+            Self::MessageTooLong { len } => {
+                write!(
+                    f,
+                    "more data written than the message length of {} bytes",
+                    len
+                )
+            }
+            Self::MessageTooShort { len, written } => write!(
+                f,
+                "message finished after {} of {} bytes, the rest was filled with zeros",
+                written, len,
+            ),
+            Self::ShortSource { length, read } => write!(
+                f,
+                "source ended after {} of {} bytes, the rest was filled with zeros",
+                read, length,
+            ),
+            Self::LongSource { length } => {
+                write!(
+                    f,
+                    "source holds more than the message length of {} bytes",
+                    length
+                )
+            }
+            Self::PacketCountTooLarge { count } => write!(f, "packet count {} too large", count),
+            Self::UnescapedCtrlSeq { packet } => {
+                write!(f, "packet {} starts with control sequence", packet)
+            }
+            Self::Poisoned {
+                remaining: Some(remaining),
+            } => write!(
+                f,
+                "stream poisoned by a failed write, the card waits for {} more packets",
+                remaining,
+            ),
+            Self::Poisoned { remaining: None } => write!(
+                f,
+                "stream poisoned by a failed write, the card waits for an unknown number of \
+                 packets",
+            ),
+            Self::InvalidCount { len, offset } => write!(
+                f,
+                "protocol error: expected packet count at offset {}, got a packet of {} bytes",
+                offset, len,
+            ),
+            Self::LongPacket {
+                len,
+                packet_size,
+                offset,
+            } => write!(
+                f,
+                "packet of {} bytes at offset {} exceeds the packet size of {} bytes",
+                len, offset, packet_size,
+            ),
+            Self::ShortPacket {
+                len,
+                remaining,
+                offset,
+                message,
+            } => write!(
+                f,
+                "protocol error: short packet of {} bytes with {} packets remaining at offset {} \
+                 (message {})",
+                len, remaining, offset, message,
+            ),
+            Self::Truncated { remaining, message } => write!(
+                f,
+                "stream ended with {} packets of message {} missing",
+                remaining, message,
+            ),
+            Self::CrcMismatch {
+                expected,
+                actual,
+                offset,
+                message,
+            } => write!(
+                f,
+                "crc mismatch in message {} at offset {}: expected {:#010x}, got {:#010x}",
+                message, offset, expected, actual,
+            ),
+            Self::MissingTrailer { offset, message } => write!(
+                f,
+                "message {} at offset {} is too short for its trailer",
+                message, offset,
+            ),
+            Self::MissingHeader { offset, message } => write!(
+                f,
+                "message {} at offset {} is too short for its sequence number",
+                message, offset,
+            ),
+            Self::SequenceMismatch {
+                expected,
+                found,
+                offset,
+                message,
+            } => write!(
+                f,
+                "sequence mismatch in message {} at offset {}: expected {}, found {}",
+                message, offset, expected, found,
+            ),
+        }
+    }
+}
+
+impl Error for H2cError {}
+
+impl From<H2cError> for io::Error {
+    fn from(err: H2cError) -> Self {
+        let kind = match err {
+            H2cError::EmptyMessage
+            | H2cError::MessageTooLong { .. }
+            | H2cError::MessageTooShort { .. }
+            | H2cError::ShortSource { .. }
+            | H2cError::LongSource { .. }
+            | H2cError::PacketCountTooLarge { .. }
+            | H2cError::UnescapedCtrlSeq { .. } => io::ErrorKind::InvalidInput,
+            H2cError::Poisoned { .. } => io::ErrorKind::Other,
+            _ => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, err)
+    }
+}
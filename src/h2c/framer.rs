@@ -0,0 +1,232 @@
+use super::H2cError;
+use crate::{framing::SEQUENCE_LEN, EscapeMode, Integrity, StreamGeometry, CTRL_SEQ, ESCAPE_FLAG};
+use std::{io, ops::Deref};
+
+/// H2C message framing shared by the stream types: the packet count in front of a message, the
+/// optional sequence number and the optional trailer.
+#[derive(Debug)]
+pub struct Framer {
+    pub geometry: StreamGeometry,
+    pub escape_mode: EscapeMode,
+    pub integrity: Integrity,
+    pub sequence_numbers: bool,
+    pub next_sequence: u32,
+}
+
+impl Framer {
+    pub fn new(geometry: StreamGeometry) -> Self {
+        Self {
+            geometry,
+            escape_mode: EscapeMode::Disabled,
+            integrity: Integrity::None,
+            sequence_numbers: false,
+            next_sequence: 0,
+        }
+    }
+
+    /// Length of a message with `len` bytes of data on the wire.
+    pub fn framed_len(&self, len: usize) -> usize {
+        let header_len = if self.sequence_numbers {
+            SEQUENCE_LEN
+        } else {
+            0
+        };
+        header_len + len + self.integrity.trailer_len()
+    }
+
+    /// Returns the packet count word of a message with `len` bytes of data. A count that reaches
+    /// into [`ESCAPE_FLAG`] is rejected whatever the escape mode.
+    pub fn message_count(&self, len: usize) -> io::Result<[u8; 4]> {
+        let count = self.geometry.packet_count(self.framed_len(len));
+        match u32::try_from(count) {
+            Ok(count) if count & ESCAPE_FLAG == 0 => self.count(count),
+            _ => Err(H2cError::PacketCountTooLarge { count }.into()),
+        }
+    }
+
+    /// Returns the packet count word announcing `count` packets.
+    ///
+    /// In [`EscapeMode::Escaped`], [`ESCAPE_FLAG`] is added to the count, in
+    /// [`EscapeMode::Strict`], nothing is escaped. In both, the count must not have that bit set
+    /// itself. In [`EscapeMode::Disabled`], the count is passed on unchanged.
+    pub fn count(&self, count: u32) -> io::Result<[u8; 4]> {
+        let count = match self.escape_mode {
+            EscapeMode::Escaped | EscapeMode::Strict if count & ESCAPE_FLAG != 0 => {
+                return Err(H2cError::PacketCountTooLarge {
+                    count: count as usize,
+                }
+                .into());
+            }
+            EscapeMode::Escaped => count | ESCAPE_FLAG,
+            EscapeMode::Disabled | EscapeMode::Strict => count,
+        };
+
+        Ok(u32::to_le_bytes(count))
+    }
+
+    /// In [`EscapeMode::Strict`], fails if a packet other than the last one of the message
+    /// carrying the concatenation of `parts` starts with [`CTRL_SEQ`].
+    pub fn check<T>(&self, parts: &[T]) -> io::Result<()>
+    where
+        T: Deref<Target = [u8]>,
+    {
+        if self.escape_mode != EscapeMode::Strict {
+            return Ok(());
+        }
+
+        // The sequence number is part of the first packet
+        let sequence = u32::to_le_bytes(self.next_sequence);
+        let header = match self.sequence_numbers {
+            true => &sequence[..],
+            false => &[],
+        };
+        let packet_size = self.geometry.packet_size();
+        let len = parts.iter().map(|part| part.len()).sum();
+        let last = (self.framed_len(len) - 1) / packet_size * packet_size;
+        if let Some(packet) = (0..last).step_by(packet_size).position(|start| {
+            let data = parts.iter().flat_map(|part| part.iter());
+            let packet = header.iter().chain(data).skip(start);
+            packet.take(CTRL_SEQ.len()).eq(&CTRL_SEQ)
+        }) {
+            return Err(H2cError::UnescapedCtrlSeq { packet }.into());
+        }
+
+        Ok(())
+    }
+
+    /// Takes the sequence number of the next message, if enabled.
+    pub fn next_header(&mut self) -> Header {
+        if !self.sequence_numbers {
+            return Header {
+                bytes: [0; SEQUENCE_LEN],
+                len: 0,
+            };
+        }
+
+        let bytes = u32::to_le_bytes(self.next_sequence);
+        self.next_sequence = self.next_sequence.wrapping_add(1);
+        Header {
+            bytes,
+            len: SEQUENCE_LEN,
+        }
+    }
+
+    /// Returns the trailer for a message whose CRC32C is computed by `crc`, if enabled.
+    pub fn trailer(&self, crc: impl FnOnce() -> u32) -> Option<[u8; 4]> {
+        match self.integrity {
+            Integrity::None => None,
+            Integrity::Crc32c => Some(u32::to_le_bytes(crc())),
+        }
+    }
+}
+
+/// Bytes in front of the data of a message.
+#[derive(Debug, Clone, Copy)]
+pub struct Header {
+    bytes: [u8; SEQUENCE_LEN],
+    len: usize,
+}
+
+impl Header {
+    pub fn as_slice(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+
+    /// CRC32C over the header, the seed for the checksum of the data.
+    pub fn crc(&self) -> u32 {
+        crc32c::crc32c(self.as_slice())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn framer(escape_mode: EscapeMode) -> Framer {
+        let mut framer = Framer::new(StreamGeometry::new(64, 64).unwrap());
+        framer.escape_mode = escape_mode;
+        framer
+    }
+
+    fn too_large(result: io::Result<[u8; 4]>) -> H2cError {
+        let err = result.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        err.into_inner()
+            .unwrap()
+            .downcast::<H2cError>()
+            .map(|err| *err)
+            .unwrap()
+    }
+
+    /// A message whose second packet starts with [`CTRL_SEQ`], or its last one with `last` set.
+    fn ctrl_in_packet(last: bool) -> Vec<u8> {
+        let mut message = vec![0; if last { 100 } else { 200 }];
+        message[64..68].copy_from_slice(&CTRL_SEQ);
+        message
+    }
+
+    #[test]
+    fn disabled_passes_counts() {
+        let framer = framer(EscapeMode::Disabled);
+        assert_eq!(framer.message_count(65).unwrap(), u32::to_le_bytes(2));
+        assert_eq!(
+            framer.count(3 | ESCAPE_FLAG).unwrap(),
+            u32::to_le_bytes(3 | ESCAPE_FLAG)
+        );
+        framer.check(&[&ctrl_in_packet(false)[..]]).unwrap();
+    }
+
+    #[test]
+    fn escaped_adds_flag() {
+        let framer = framer(EscapeMode::Escaped);
+        assert_eq!(
+            framer.message_count(65).unwrap(),
+            u32::to_le_bytes(2 | ESCAPE_FLAG)
+        );
+        assert_eq!(framer.count(3).unwrap(), u32::to_le_bytes(3 | ESCAPE_FLAG));
+        assert_eq!(
+            too_large(framer.count(3 | ESCAPE_FLAG)),
+            H2cError::PacketCountTooLarge {
+                count: (3 | ESCAPE_FLAG) as usize
+            }
+        );
+        framer.check(&[&ctrl_in_packet(false)[..]]).unwrap();
+    }
+
+    #[test]
+    fn strict_rejects_flag_and_ctrl_seq() {
+        let mut framer = framer(EscapeMode::Strict);
+        assert_eq!(framer.message_count(65).unwrap(), u32::to_le_bytes(2));
+        assert_eq!(framer.count(3).unwrap(), u32::to_le_bytes(3));
+        assert_eq!(
+            too_large(framer.count(3 | ESCAPE_FLAG)),
+            H2cError::PacketCountTooLarge {
+                count: (3 | ESCAPE_FLAG) as usize
+            }
+        );
+
+        let message = ctrl_in_packet(false);
+        let err = framer.check(&[&message[..10], &message[10..]]).unwrap_err();
+        assert_eq!(
+            err.get_ref().unwrap().downcast_ref(),
+            Some(&H2cError::UnescapedCtrlSeq { packet: 1 })
+        );
+        // The last packet may start with the control sequence
+        framer.check(&[&ctrl_in_packet(true)[..]]).unwrap();
+        // The sequence number shifts the data into the next packet
+        framer.sequence_numbers = true;
+        framer.check(&[&message[..]]).unwrap();
+    }
+
+    #[test]
+    fn message_count_rejects_flag() {
+        let framer = framer(EscapeMode::Disabled);
+        let len = (ESCAPE_FLAG as usize) * 64;
+        assert_eq!(
+            too_large(framer.message_count(len)),
+            H2cError::PacketCountTooLarge {
+                count: ESCAPE_FLAG as usize
+            }
+        );
+    }
+}
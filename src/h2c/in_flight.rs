@@ -0,0 +1,160 @@
+use super::{buf::Buf, H2cError};
+use crate::{StreamGeometry, ESCAPE_FLAG};
+use std::io::{self, Write};
+
+/// Tracks the packets of the current message the card still waits for, to tell whether a failed
+/// write left the card in the middle of a message. The stream is poisoned then, because the card
+/// would take whatever comes next for the rest of that message.
+#[derive(Debug)]
+pub struct InFlight {
+    packet_size: usize,
+    /// Packets announced by the last packet count that were not written yet, `None` if unknown.
+    remaining: Option<u32>,
+    poisoned: bool,
+    /// An operation has started and not completed, set only by the async stream, whose futures
+    /// may be dropped halfway.
+    busy: bool,
+    /// The file reports a failed write on a later call, so a write error may belong to any
+    /// packet written before.
+    deferred_errors: bool,
+}
+
+impl InFlight {
+    pub fn new(geometry: StreamGeometry) -> Self {
+        Self {
+            packet_size: geometry.packet_size(),
+            remaining: Some(0),
+            poisoned: false,
+            busy: false,
+            deferred_errors: false,
+        }
+    }
+
+    pub fn remaining(&self) -> Option<u32> {
+        self.remaining
+    }
+
+    pub fn deferred_errors(&self) -> bool {
+        self.deferred_errors
+    }
+
+    pub fn set_deferred_errors(&mut self, deferred_errors: bool) {
+        self.deferred_errors = deferred_errors;
+    }
+
+    /// Whether a failed write or an interrupted operation left a message unfinished.
+    pub fn poisoned(&self) -> bool {
+        self.poisoned || self.busy && self.remaining != Some(0)
+    }
+
+    /// Fails with [`H2cError::Poisoned`] if the stream is poisoned.
+    pub fn check(&self) -> Result<(), H2cError> {
+        match self.poisoned() {
+            true => Err(H2cError::Poisoned {
+                remaining: self.remaining,
+            }),
+            false => Ok(()),
+        }
+    }
+
+    /// Starts an operation that may be interrupted. Until [`end`](Self::end), the stream counts
+    /// as poisoned whenever a message is unfinished.
+    #[cfg(feature = "tokio")]
+    pub fn begin(&mut self) -> Result<(), H2cError> {
+        self.check()?;
+        self.busy = true;
+        Ok(())
+    }
+
+    /// Completes the operation started by `begin`.
+    #[cfg(feature = "tokio")]
+    pub fn end(&mut self) {
+        self.busy = false;
+    }
+
+    /// Records a packet count written to the card.
+    pub fn announce(&mut self, count: [u8; 4]) {
+        self.remaining = Some(u32::from_le_bytes(count) & !ESCAPE_FLAG);
+    }
+
+    /// Completes the write of the packet count `count`, which was the only content of `buf`,
+    /// with the `result` of writing out `buf`.
+    ///
+    /// The count is announced if it left the buffer. Otherwise it did not reach the card and is
+    /// dropped, so that it is not written in front of the next data.
+    pub fn count_written(
+        &mut self,
+        count: [u8; 4],
+        buf: &mut Buf,
+        result: io::Result<()>,
+    ) -> io::Result<()> {
+        if buf.len() == 0 {
+            self.announce(count);
+        } else {
+            buf.clear();
+        }
+        result.map_err(|err| self.fail(err))
+    }
+
+    /// Records a write of `len` bytes, which the card receives as packets of at most the packet
+    /// size.
+    pub fn written(&mut self, len: usize) {
+        let packets = u32::try_from(len.div_ceil(self.packet_size)).unwrap_or(u32::MAX);
+        self.remaining = self
+            .remaining
+            .map(|remaining| remaining.saturating_sub(packets));
+    }
+
+    /// Poisons the stream if the failed write `err` left a message unfinished, returns `err`.
+    /// With deferred errors, this is [`fail_unknown`](Self::fail_unknown).
+    pub fn fail(&mut self, err: io::Error) -> io::Error {
+        if self.deferred_errors {
+            return self.fail_unknown(err);
+        }
+        if self.remaining != Some(0) {
+            self.poisoned = true;
+        }
+        err
+    }
+
+    /// Poisons the stream for the failed write `err`, of which it is unknown which packets
+    /// reached the card, returns `err`.
+    pub fn fail_unknown(&mut self, err: io::Error) -> io::Error {
+        self.remaining = None;
+        self.poisoned = true;
+        err
+    }
+
+    /// Clears the poisoned state, the packets still outstanding are kept.
+    pub fn recover(&mut self) {
+        self.poisoned = false;
+        self.busy = false;
+    }
+
+    /// Clears the poisoned state, the card expects a packet count.
+    pub fn reset(&mut self) {
+        self.recover();
+        self.remaining = Some(0);
+    }
+}
+
+/// Writer that records the writes to `file` in an [`InFlight`].
+pub struct Tracked<'a, W> {
+    pub file: &'a mut W,
+    pub in_flight: &'a mut InFlight,
+}
+
+impl<W> Write for Tracked<'_, W>
+where
+    W: Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.file.write(buf)?;
+        self.in_flight.written(len);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
@@ -0,0 +1,154 @@
+use super::{H2cError, HostToCardStream};
+use std::io::{self, Read, Write};
+
+/// Source of the zeros that fill an incomplete message.
+pub(super) const ZEROS: [u8; 512] = [0; 512];
+
+/// Writes a single framed message of a known length to a [`HostToCardStream`].
+///
+/// Returned by [`HostToCardStream::begin_message`], which has already written the packet count
+/// and the sequence number. A write of more data than the rest of the message fails with
+/// [`H2cError::MessageTooLong`] and writes nothing. [`finish`](Self::finish) appends the trailer
+/// and writes out the message.
+///
+/// If the message is finished or dropped before all of its data was written, the rest of the
+/// message, including the trailer, is filled with zeros, so that the card receives all announced
+/// packets and a receiver checking the integrity drops the message. [`finish`](Self::finish) then
+/// fails with [`H2cError::MessageTooShort`], on drop the error is returned by the next call to
+/// the stream.
+pub struct MessageWriter<'a, F>
+where
+    F: Write,
+{
+    stream: &'a mut HostToCardStream<F>,
+    len: usize,
+    written: usize,
+    crc: u32,
+    finished: bool,
+}
+
+impl<'a, F> MessageWriter<'a, F>
+where
+    F: Write,
+{
+    pub(super) fn new(stream: &'a mut HostToCardStream<F>, len: usize, crc: u32) -> Self {
+        Self {
+            stream,
+            len,
+            written: 0,
+            crc,
+            finished: false,
+        }
+    }
+
+    /// Bytes of the message still to be written.
+    pub fn remaining(&self) -> usize {
+        self.len - self.written
+    }
+
+    /// Completes the message and writes it out.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.complete()
+    }
+
+    /// Fills the rest of the message, including the trailer, with zeros and writes it out.
+    pub(super) fn fill(&mut self) -> io::Result<()> {
+        self.finished = true;
+        let mut rest = self.len - self.written + self.stream.framer.integrity.trailer_len();
+        while rest > 0 {
+            let len = usize::min(rest, ZEROS.len());
+            self.stream.buffer(&ZEROS[..len], true)?;
+            rest -= len;
+        }
+        self.stream.end_message()
+    }
+
+    /// Reads the rest of the message from `source` directly into the buffer of the stream.
+    /// Returns whether `source` holds more data than the message.
+    pub(super) fn read_from<R: Read>(&mut self, mut source: R) -> io::Result<bool> {
+        let block_size = self.stream.framer.geometry.block_size();
+        loop {
+            let remaining = self.remaining();
+            let buf = &mut self.stream.buf;
+
+            // Reads end at block boundaries, so that only whole packets are written before the
+            // end of the message. The read reaching the end goes past it, to detect more data.
+            let end = (buf.len() + remaining + 1).next_multiple_of(block_size);
+            let end = usize::min(end, buf.capacity());
+            if end == buf.len() {
+                self.stream.write_out(false)?;
+                continue;
+            }
+
+            let start = buf.len();
+            let read = buf.read_from(&mut source, end - start)?;
+            let count = usize::min(read, remaining);
+            buf.truncate(start + count);
+            self.crc = crc32c::crc32c_append(self.crc, &buf.data()[start..]);
+            self.written += count;
+            if read > remaining {
+                return Ok(true);
+            }
+            if read < end - start {
+                return Ok(false);
+            }
+            self.stream.apply_flush_policy(true)?;
+        }
+    }
+
+    fn complete(&mut self) -> io::Result<()> {
+        if self.written < self.len {
+            self.fill()?;
+            return Err(H2cError::MessageTooShort {
+                len: self.len,
+                written: self.written,
+            }
+            .into());
+        }
+
+        self.finished = true;
+        let crc = self.crc;
+        if let Some(trailer) = self.stream.framer.trailer(|| crc) {
+            self.stream.buffer(&trailer, true)?;
+        }
+        self.stream.end_message()
+    }
+}
+
+impl<F> Write for MessageWriter<'_, F>
+where
+    F: Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if buf.len() > self.remaining() {
+            return Err(H2cError::MessageTooLong { len: self.len }.into());
+        }
+
+        self.stream.buffer(buf, true)?;
+        self.crc = crc32c::crc32c_append(self.crc, buf);
+        self.written += buf.len();
+        Ok(buf.len())
+    }
+
+    /// Writes the whole packets of the message written so far. The rest is written with the next
+    /// packets or by [`finish`](MessageWriter::finish), so that the packets are not split.
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.write_out(false)
+    }
+}
+
+impl<F> Drop for MessageWriter<'_, F>
+where
+    F: Write,
+{
+    fn drop(&mut self) {
+        if !self.finished {
+            if let Err(err) = self.complete() {
+                self.stream.deferred_error = Some(err);
+            }
+        }
+    }
+}
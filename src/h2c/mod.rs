@@ -1,29 +1,157 @@
+#[cfg(feature = "tokio")]
+mod async_stream;
 mod buf;
+mod decoder;
+mod error;
+mod framer;
+mod in_flight;
+mod message;
+mod writer;
 
-use self::buf::Buf;
+#[cfg(feature = "tokio")]
+pub use self::async_stream::AsyncHostToCardStream;
+pub use self::{
+    decoder::{H2cDecoder, H2cMessage},
+    error::H2cError,
+    message::MessageWriter,
+};
+
+use self::{
+    buf::Buf,
+    framer::Framer,
+    in_flight::{InFlight, Tracked},
+    message::ZEROS,
+    writer::WriterThread,
+};
+use crate::{AlignedBuf, EscapeMode, Integrity, StreamGeometry};
 use anyhow::Result;
 use std::{
-    io::{self, Read, Write},
-    time::Instant,
+    io::{self, IoSlice, Read, Write},
+    mem, thread,
+    time::{Duration, Instant},
 };
 
+/// Decides when a [`HostToCardStream`] writes buffered data to the file.
+///
+/// Whatever the policy, a full buffer is written and every message written with
+/// [`write_remaining`](HostToCardStream::write_remaining),
+/// [`write_complete_stream`](HostToCardStream::write_complete_stream) or a [`MessageWriter`] is
+/// flushed at its end. Inside such a message only whole packets are written early, so that its
+/// packets are not split. Data written with plain [`write`](Write::write) calls is written as it
+/// is, a partial packet included, by [`Immediate`](Self::Immediate) and
+/// [`Linger`](Self::Linger).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlushPolicy {
+    /// Every write is passed on to the file right away.
+    Immediate,
+    /// Whole packets are written once the buffer holds at least this many bytes.
+    Threshold(usize),
+    /// The buffer is written by the first write at least this long after the last write to the
+    /// file. Without further writes, [`flush_expired`](HostToCardStream::flush_expired) does
+    /// the same.
+    ///
+    /// There is no timer: the deadline is only checked by the next write and by
+    /// [`flush_expired`](HostToCardStream::flush_expired), so the data of an idle stream stays
+    /// in the buffer until one of them is called.
+    Linger(Duration),
+    /// Only [`flush`](Write::flush) and a full buffer write to the file.
+    Manual,
+}
+
+/// Decides what dropping a [`HostToCardStream`] does if writing out the rest of its data fails.
+///
+/// [`finish`](HostToCardStream::finish) returns that error instead. A
+/// [poisoned](HostToCardStream::is_poisoned) stream without buffered data is dropped silently,
+/// its error was returned already.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DropPolicy {
+    /// The error is printed to stderr.
+    #[default]
+    Log,
+    /// Panics in debug builds, unless the thread is already panicking, and prints the error to
+    /// stderr otherwise.
+    PanicInDebug,
+    /// The error is dropped.
+    Ignore,
+}
+
 pub struct HostToCardStream<F: Write> {
     buf: Buf,
-    file: F,
+    output: Output<F>,
     last_write_to_file: Instant,
-    flush_threshold: usize,
+    flush_policy: FlushPolicy,
+    drop_policy: DropPolicy,
+    framer: Framer,
+    in_flight: InFlight,
+    /// Error of a [`MessageWriter`] that was dropped, returned by the next call.
+    deferred_error: Option<io::Error>,
 }
 
 impl<F> HostToCardStream<F>
 where
     F: Write + 'static,
 {
+    /// Creates a stream with a [`FlushPolicy::Threshold`] of `flush_threshold` bytes.
     pub fn new(file: F, capacity: usize, flush_threshold: usize) -> Result<Self> {
+        Self::with_geometry(file, capacity, flush_threshold, StreamGeometry::default())
+    }
+
+    /// Like [`new`](Self::new), but for a queue with the given packet size and alignment.
+    /// `capacity` must be a multiple of the alignment and hold at least one packet.
+    pub fn with_geometry(
+        file: F,
+        capacity: usize,
+        flush_threshold: usize,
+        geometry: StreamGeometry,
+    ) -> Result<Self> {
+        anyhow::ensure!(capacity >= geometry.block_size(), "capacity too small");
+
+        Ok(Self {
+            buf: Buf::new(capacity, geometry)?,
+            output: Output::File(file),
+            last_write_to_file: Instant::now(),
+            flush_policy: FlushPolicy::Threshold(flush_threshold),
+            drop_policy: DropPolicy::default(),
+            framer: Framer::new(geometry),
+            in_flight: InFlight::new(geometry),
+            deferred_error: None,
+        })
+    }
+}
+
+impl<F> HostToCardStream<F>
+where
+    F: Write + Send + 'static,
+{
+    /// Like [`with_geometry`](Self::with_geometry), but with `buffers` buffers of `capacity`
+    /// bytes and a dedicated thread that writes them to `file`.
+    ///
+    /// While the thread writes a buffer, the stream fills the next one, so copying and DMA
+    /// overlap. Buffers are handed to the thread as the [`FlushPolicy`] decides and at the end of
+    /// every message, [`flush`](Write::flush) waits until the thread has written everything.
+    /// Write errors of the thread are returned by the next call.
+    pub fn with_writer_thread(
+        file: F,
+        capacity: usize,
+        flush_threshold: usize,
+        geometry: StreamGeometry,
+        buffers: usize,
+    ) -> Result<Self> {
+        anyhow::ensure!(buffers >= 2, "at least two buffers are needed");
+        anyhow::ensure!(capacity >= geometry.block_size(), "capacity too small");
+
+        let free = (1..buffers)
+            .map(|_| Buf::new(capacity, geometry))
+            .collect::<Result<_>>()?;
         Ok(Self {
-            buf: Buf::new(capacity)?,
-            file,
+            buf: Buf::new(capacity, geometry)?,
+            output: Output::Thread(WriterThread::spawn(file, free)?),
             last_write_to_file: Instant::now(),
-            flush_threshold,
+            flush_policy: FlushPolicy::Threshold(flush_threshold),
+            drop_policy: DropPolicy::default(),
+            framer: Framer::new(geometry),
+            in_flight: InFlight::new(geometry),
+            deferred_error: None,
         })
     }
 }
@@ -32,17 +160,135 @@ impl<F> HostToCardStream<F>
 where
     F: Write,
 {
-    pub fn write_complete_stream(&mut self, mut buf: impl Read, length: usize) -> io::Result<()> {
-        if length == 0 {
-            panic!("length is zero");
+    pub fn geometry(&self) -> StreamGeometry {
+        self.framer.geometry
+    }
+
+    pub fn flush_policy(&self) -> FlushPolicy {
+        self.flush_policy
+    }
+
+    pub fn set_flush_policy(&mut self, policy: FlushPolicy) {
+        self.flush_policy = policy;
+    }
+
+    pub fn drop_policy(&self) -> DropPolicy {
+        self.drop_policy
+    }
+
+    pub fn set_drop_policy(&mut self, policy: DropPolicy) {
+        self.drop_policy = policy;
+    }
+
+    /// Returns the file, or `None` with a [writer thread](Self::with_writer_thread), which owns
+    /// the file until [`finish`](Self::finish).
+    pub fn get_ref(&self) -> Option<&F> {
+        match &self.output {
+            Output::File(file) => Some(file),
+            _ => None,
+        }
+    }
+
+    /// Like [`get_ref`](Self::get_ref). Data written to the file directly bypasses the buffer of
+    /// the stream and the framing.
+    pub fn get_mut(&mut self) -> Option<&mut F> {
+        match &mut self.output {
+            Output::File(file) => Some(file),
+            _ => None,
+        }
+    }
+
+    pub fn escape_mode(&self) -> EscapeMode {
+        self.framer.escape_mode
+    }
+
+    /// Sets how packets starting with [`CTRL_SEQ`](crate::CTRL_SEQ) are protected. See
+    /// [`EscapeMode`].
+    pub fn set_escape_mode(&mut self, mode: EscapeMode) {
+        self.framer.escape_mode = mode;
+    }
+
+    pub fn integrity(&self) -> Integrity {
+        self.framer.integrity
+    }
+
+    /// Sets the integrity check appended to every message written with
+    /// [`write_remaining`](Self::write_remaining) or
+    /// [`write_complete_stream`](Self::write_complete_stream).
+    pub fn set_integrity(&mut self, integrity: Integrity) {
+        self.framer.integrity = integrity;
+    }
+
+    pub fn sequence_numbers(&self) -> bool {
+        self.framer.sequence_numbers
+    }
+
+    /// Enables a little-endian sequence number in front of every message written with
+    /// [`write_remaining`](Self::write_remaining) or
+    /// [`write_complete_stream`](Self::write_complete_stream). It starts at zero, is incremented
+    /// for every message and wraps around. The card passes it through unchanged, the
+    /// [`CardToHostStream`](crate::CardToHostStream) of the receiving side strips and checks it.
+    pub fn set_sequence_numbers(&mut self, enabled: bool) {
+        self.framer.sequence_numbers = enabled;
+    }
+
+    /// Sequence number the next message will carry.
+    pub fn next_sequence(&self) -> u32 {
+        self.framer.next_sequence
+    }
+
+    /// Writes out the buffer, waits until everything is written and returns the file.
+    ///
+    /// Unlike dropping the stream, this returns the error of the last writes, including an error
+    /// left by a dropped [`MessageWriter`]. On error, the stream and the file are dropped without
+    /// applying the [`DropPolicy`], and without writing what is left in the buffer again.
+    pub fn finish(mut self) -> io::Result<F> {
+        if let Err(err) = self.flush() {
+            self.drop_policy = DropPolicy::Ignore;
+            self.buf.clear();
+            self.output = Output::Finished;
+            return Err(err);
         }
+        match mem::replace(&mut self.output, Output::Finished) {
+            Output::File(file) => Ok(file),
+            Output::Thread(thread) => thread.finish(),
+            Output::Finished => unreachable!(),
+        }
+    }
 
-        self.write_remaining_packet_count(usize::div_ceil(length, 4096) as u32)?;
-        let written = io::copy(&mut buf, self)?;
-        self.flush()?;
+    /// Writes `length` bytes read from `buf` as one message.
+    ///
+    /// Fails with [`H2cError::EmptyMessage`] before anything is written if `length` is zero. If
+    /// `buf` ends early, the rest of the message is filled with zeros and the call fails with
+    /// [`H2cError::ShortSource`]. If `buf` holds more data, the message is sent with `length`
+    /// bytes and the call fails with [`H2cError::LongSource`]. In both cases the stream stays
+    /// usable for the next message.
+    ///
+    /// The data is read from `buf` straight into the internal buffer, in reads that end at packet
+    /// and alignment boundaries of the buffer. Without sequence numbers, which shift the data,
+    /// the reads are aligned, so `buf` may be a file opened with `O_DIRECT`. The last read may
+    /// ask for more than the rest of the message, to find out whether `buf` ends with it.
+    pub fn write_complete_stream(&mut self, mut buf: impl Read, length: usize) -> io::Result<()> {
+        let mut message = self.begin_message(length)?;
+        let long = message.read_from(&mut buf);
+        let remaining = message.remaining();
+        let completed = match remaining {
+            0 => message.finish(),
+            _ => message.fill(),
+        };
 
-        if written != length as u64 {
-            panic!("written bytes does not match length");
+        // A failed read or write of the data comes first, completing the message after it may
+        // only fail because of it
+        let long = long?;
+        completed?;
+        if remaining > 0 {
+            let read = length - remaining;
+            return Err(H2cError::ShortSource { length, read }.into());
+        }
+
+        // Data left in the source means that the length was wrong
+        if long {
+            return Err(H2cError::LongSource { length }.into());
         }
 
         Ok(())
@@ -50,39 +296,288 @@ where
 
     /// Use this to write remaining packets and finish the stream.
     ///
-    /// # Panics
-    ///
-    /// Panics if `remaining` is empty.
+    /// Fails with [`H2cError::EmptyMessage`] before anything is written if `remaining` is empty
+    /// or, in [`EscapeMode::Strict`], with [`H2cError::UnescapedCtrlSeq`] if a packet other than
+    /// the last one starts with [`CTRL_SEQ`](crate::CTRL_SEQ).
     pub fn write_remaining(&mut self, remaining: &[u8]) -> io::Result<()> {
-        if remaining.is_empty() {
-            panic!("remaining data is empty");
+        self.write_message_vectored(&[IoSlice::new(remaining)])
+    }
+
+    /// Writes the concatenation of `parts` as one message, like
+    /// [`write_remaining`](Self::write_remaining). The parts are gathered in the internal buffer,
+    /// they are not joined first.
+    pub fn write_message_vectored(&mut self, parts: &[IoSlice<'_>]) -> io::Result<()> {
+        let len = parts.iter().map(|part| part.len()).sum();
+        if len == 0 {
+            return Err(H2cError::EmptyMessage.into());
         }
 
-        // Calculate count of remaining packets
-        let remaining_packet_count = usize::div_ceil(remaining.len(), 4096) as u32;
+        self.framer.check(parts)?;
 
         // Write remaining packets count
-        self.write_remaining_packet_count(remaining_packet_count)?;
+        let count = self.framer.message_count(len)?;
+        self.write_count(count)?;
 
         // Write remaining data
-        self.write_all(remaining)?;
-        self.flush()?;
+        let header = self.framer.next_header();
+        self.buffer(header.as_slice(), true)?;
+        for part in parts {
+            self.buffer(part, true)?;
+        }
+        if let Some(trailer) = self.framer.trailer(|| {
+            parts
+                .iter()
+                .fold(header.crc(), |crc, part| crc32c::crc32c_append(crc, part))
+        }) {
+            self.buffer(&trailer, true)?;
+        }
+        self.end_message()
+    }
 
-        Ok(())
+    /// Writes `message` as one message, like [`write_remaining`](Self::write_remaining), but
+    /// without copying its whole packets into the internal buffer.
+    ///
+    /// After the packet count, the part of the message made of whole packets and alignment
+    /// blocks is written straight from `message` to the file. Only the rest and the trailer are
+    /// copied. The 4 byte packet count is written on its own, so the file must not be opened
+    /// with `O_DIRECT`. With sequence numbers, which shift the data, with a
+    /// [writer thread](Self::with_writer_thread) or if `message` is aligned less than the queue,
+    /// the whole message is copied.
+    pub fn write_message_aligned(&mut self, message: &AlignedBuf) -> io::Result<()> {
+        if message.is_empty() {
+            return Err(H2cError::EmptyMessage.into());
+        }
+        self.framer.check(&[&message[..]])?;
+
+        let count = self.framer.message_count(message.len())?;
+        self.write_count(count)?;
+        let header = self.framer.next_header();
+
+        let align = self.framer.geometry.align();
+        let direct = match &mut self.output {
+            Output::File(file) if header.as_slice().is_empty() && message.align() >= align => {
+                // The buffer is empty after the packet count
+                let block_size = self.framer.geometry.block_size();
+                let len = message.len() / block_size * block_size;
+                self.last_write_to_file = Instant::now();
+                let mut file = Tracked {
+                    file,
+                    in_flight: &mut self.in_flight,
+                };
+                file.write_all(&message[..len])
+                    .map_err(|err| self.in_flight.fail(err))?;
+                len
+            }
+            _ => {
+                self.buffer(header.as_slice(), true)?;
+                0
+            }
+        };
+
+        self.buffer(&message[direct..], true)?;
+        if let Some(trailer) = self
+            .framer
+            .trailer(|| crc32c::crc32c_append(header.crc(), message))
+        {
+            self.buffer(&trailer, true)?;
+        }
+        self.end_message()
+    }
+
+    /// Starts a message of `len` bytes and returns a writer for its data. See [`MessageWriter`].
+    ///
+    /// The packet count and the sequence number are written right away. Unlike with
+    /// [`write_remaining`](Self::write_remaining), the data is not checked in
+    /// [`EscapeMode::Strict`].
+    pub fn begin_message(&mut self, len: usize) -> io::Result<MessageWriter<'_, F>> {
+        if len == 0 {
+            return Err(H2cError::EmptyMessage.into());
+        }
+
+        let count = self.framer.message_count(len)?;
+        self.write_count(count)?;
+        let header = self.framer.next_header();
+        self.buffer(header.as_slice(), true)?;
+
+        Ok(MessageWriter::new(self, len, header.crc()))
     }
 
     /// Use this to write the count of remaining packets. This is useful when you know early on
     /// how many packets you will be writing. The stream will be finished when the count of packets
     /// is reached.
+    ///
+    /// In [`EscapeMode::Escaped`], [`ESCAPE_FLAG`](crate::ESCAPE_FLAG) is added to the count. In
+    /// that mode and in [`EscapeMode::Strict`], the call fails with
+    /// [`H2cError::PacketCountTooLarge`] if `count` has that bit set itself. In
+    /// [`EscapeMode::Disabled`], `count` is written unchanged.
     pub fn write_remaining_packet_count(&mut self, count: u32) -> io::Result<()> {
-        // Flush existing buffer
-        self.flush()?;
+        let count = self.framer.count(count)?;
+        self.write_count(count)
+    }
+
+    fn write_count(&mut self, count: [u8; 4]) -> io::Result<()> {
+        // Write out existing buffer
+        self.write_out(true)?;
 
         // Write count of remaining packets
-        self.buf.write_all(&u32::to_le_bytes(count))?;
-        self.flush()?;
+        self.buf.write_all(&count)?;
+        let result = self.write_out(true);
+        self.in_flight.count_written(count, &mut self.buf, result)
+    }
 
-        Ok(())
+    /// Whether a write to the device failed in the middle of a message. A poisoned stream fails
+    /// every call with [`H2cError::Poisoned`], until [`pad_message`](Self::pad_message) or
+    /// [`reset`](Self::reset) recovers it.
+    ///
+    /// With a [writer thread](Self::with_writer_thread) or
+    /// [deferred errors](Self::set_deferred_errors), which report errors later, it is unknown
+    /// where a failed write was, so every write error poisons the stream and only
+    /// [`reset`](Self::reset) recovers it.
+    pub fn is_poisoned(&self) -> bool {
+        self.in_flight.poisoned()
+    }
+
+    pub fn deferred_errors(&self) -> bool {
+        self.in_flight.deferred_errors()
+    }
+
+    /// Declares that the file accepts writes before they are done and reports a failed write on
+    /// a later call, like `uring::UringWriter`. A write error then poisons the stream wherever it
+    /// happens, because the packets counted as written may not have reached the card.
+    pub fn set_deferred_errors(&mut self, deferred_errors: bool) {
+        self.in_flight.set_deferred_errors(deferred_errors);
+    }
+
+    /// Completes the current message with zeros, up to the packets the card still waits for. A
+    /// poisoned stream is recovered first, dropping the buffered data of the interrupted message.
+    ///
+    /// The card receives the interrupted message with zeros in place of the packets that were
+    /// lost, so a receiver checking the integrity drops it. Fails with [`H2cError::Poisoned`] if
+    /// the number of packets is unknown.
+    pub fn pad_message(&mut self) -> io::Result<()> {
+        let Some(remaining) = self.in_flight.remaining() else {
+            return Err(H2cError::Poisoned { remaining: None }.into());
+        };
+        if self.in_flight.poisoned() {
+            self.recover();
+        }
+
+        let len = remaining as usize * self.framer.geometry.packet_size();
+        let mut rest = len.saturating_sub(self.buf.len());
+        while rest > 0 {
+            let len = usize::min(rest, ZEROS.len());
+            self.buffer(&ZEROS[..len], true)?;
+            rest -= len;
+        }
+        self.end_message()
+    }
+
+    /// Recovers a poisoned stream after the queue was reset, for example stopped and started
+    /// again, so that the card expects a packet count. The buffered data is dropped.
+    pub fn reset(&mut self) {
+        self.recover();
+        self.in_flight.reset();
+    }
+
+    /// Drops the buffered data and the errors left by the failed write.
+    fn recover(&mut self) {
+        self.in_flight.recover();
+        self.buf.clear();
+        self.deferred_error = None;
+    }
+
+    /// With [`FlushPolicy::Linger`], flushes the buffer if the last write to the file is longer
+    /// ago than the linger time. Call this periodically to bound the latency of data that is not
+    /// followed by further writes.
+    pub fn flush_expired(&mut self) -> io::Result<()> {
+        match self.flush_policy {
+            FlushPolicy::Linger(linger) if self.last_write_to_file.elapsed() >= linger => {
+                self.write_out(true)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Buffers `data` and writes to the file as the flush policy demands. With `in_message`,
+    /// only whole packets are written, so that the packets of a message are not split.
+    fn buffer(&mut self, mut data: &[u8], in_message: bool) -> io::Result<()> {
+        self.in_flight.check()?;
+        self.take_deferred_error()?;
+        while !data.is_empty() {
+            let count = self.buf.write(data)?;
+            data = &data[count..];
+            if !data.is_empty() {
+                self.write_out(false)?;
+            }
+        }
+        self.apply_flush_policy(in_message)
+    }
+
+    /// Writes to the file if the flush policy demands it after data was buffered.
+    fn apply_flush_policy(&mut self, in_message: bool) -> io::Result<()> {
+        let (due, partial) = match self.flush_policy {
+            FlushPolicy::Immediate => (true, true),
+            FlushPolicy::Threshold(threshold) => (self.buf.len() >= threshold, false),
+            FlushPolicy::Linger(linger) => (self.last_write_to_file.elapsed() >= linger, true),
+            FlushPolicy::Manual => (false, false),
+        };
+        match due {
+            true => self.write_out(partial && !in_message),
+            false => Ok(()),
+        }
+    }
+
+    /// Writes the buffer to the file or hands it to the writer thread. Unless `partial` is set,
+    /// a partial packet at the end is kept.
+    fn write_out(&mut self, partial: bool) -> io::Result<()> {
+        self.in_flight.check()?;
+        self.take_deferred_error()?;
+        self.last_write_to_file = Instant::now();
+        match &mut self.output {
+            Output::File(file) => {
+                let mut file = Tracked {
+                    file,
+                    in_flight: &mut self.in_flight,
+                };
+                let result = match partial {
+                    true => self.buf.write_into(&mut file),
+                    false => self.buf.write_aligned_into(&mut file),
+                };
+                result.map_err(|err| self.in_flight.fail(err))
+            }
+            Output::Thread(thread) => {
+                let len = match partial {
+                    true => self.buf.len(),
+                    false => self.buf.aligned_len(),
+                };
+                // The thread writes the whole packets and the rest separately
+                let block_size = self.framer.geometry.block_size();
+                self.in_flight.written(len / block_size * block_size);
+                self.in_flight.written(len % block_size);
+                thread
+                    .submit(&mut self.buf, len)
+                    .map_err(|err| self.in_flight.fail_unknown(err))
+            }
+            Output::Finished => Ok(()),
+        }
+    }
+
+    /// Writes out the end of a message and flushes the file, so that an error the file reports
+    /// late is returned with the message. Write-outs within a message do not wait for the file.
+    /// The writer thread reports its errors with later calls anyway.
+    fn end_message(&mut self) -> io::Result<()> {
+        self.write_out(true)?;
+        match &mut self.output {
+            Output::File(file) => file.flush().map_err(|err| self.in_flight.fail(err)),
+            Output::Thread(_) | Output::Finished => Ok(()),
+        }
+    }
+
+    fn take_deferred_error(&mut self) -> io::Result<()> {
+        match self.deferred_error.take() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
     }
 }
 
@@ -91,17 +586,19 @@ where
     F: Write,
 {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let count = self.buf.write(buf)?;
-        if self.buf.len() >= self.flush_threshold {
-            self.flush()?;
-        }
-        Ok(count)
+        self.buffer(buf, false)?;
+        Ok(buf.len())
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        self.last_write_to_file = Instant::now();
-        self.buf.write_into(&mut self.file)?;
-        Ok(())
+        self.write_out(true)?;
+        match &mut self.output {
+            Output::File(file) => file.flush().map_err(|err| self.in_flight.fail(err)),
+            Output::Thread(thread) => thread
+                .flush()
+                .map_err(|err| self.in_flight.fail_unknown(err)),
+            Output::Finished => Ok(()),
+        }
     }
 }
 
@@ -110,6 +607,482 @@ where
     F: Write,
 {
     fn drop(&mut self) {
-        let _ = self.flush();
+        // The error that poisoned the stream was returned already, only lost data is reported
+        let pending = self.buf.len() > 0 || self.deferred_error.is_some();
+        if self.in_flight.poisoned() && !pending {
+            return;
+        }
+        let Err(err) = self.flush() else {
+            return;
+        };
+        match self.drop_policy {
+            DropPolicy::PanicInDebug if cfg!(debug_assertions) && !thread::panicking() => {
+                panic!("Failed to flush stream: {:?}", err)
+            }
+            DropPolicy::Log | DropPolicy::PanicInDebug => {
+                eprintln!("Failed to flush stream: {:?}", err)
+            }
+            DropPolicy::Ignore => {}
+        }
+    }
+}
+
+/// Where a [`HostToCardStream`] writes its buffers.
+enum Output<F> {
+    File(F),
+    Thread(WriterThread<F>),
+    /// The file was returned by [`HostToCardStream::finish`], nothing is left to write.
+    Finished,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_util::{self, messages, Capture},
+        H2cDecoder, ESCAPE_FLAG,
+    };
+    use std::{cell::RefCell, rc::Rc};
+
+    /// Device that records the length of every write and fails the write with index `fail`.
+    /// With `deferred`, the failed write is accepted and the error is reported by the next call,
+    /// like [`UringWriter`](crate::uring::UringWriter) does. `flushes` holds the number of writes
+    /// at every flush.
+    #[derive(Debug)]
+    struct Device {
+        writes: Rc<RefCell<Vec<usize>>>,
+        flushes: Vec<usize>,
+        fail: usize,
+        deferred: bool,
+        error: Option<io::Error>,
+    }
+
+    impl Write for Device {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if let Some(err) = self.error.take() {
+                return Err(err);
+            }
+            let mut writes = self.writes.borrow_mut();
+            if writes.len() == self.fail {
+                self.fail = usize::MAX;
+                match self.deferred {
+                    true => self.error = Some(io::ErrorKind::BrokenPipe.into()),
+                    false => return Err(io::ErrorKind::BrokenPipe.into()),
+                }
+            }
+            writes.push(buf.len());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.flushes.push(self.writes.borrow().len());
+            match self.error.take() {
+                Some(err) => Err(err),
+                None => Ok(()),
+            }
+        }
+    }
+
+    fn device(fail: usize, deferred: bool) -> (Device, Rc<RefCell<Vec<usize>>>) {
+        let writes = Rc::default();
+        let device = Device {
+            writes: Rc::clone(&writes),
+            flushes: Vec::new(),
+            fail,
+            deferred,
+            error: None,
+        };
+        (device, writes)
+    }
+
+    fn stream(fail: usize) -> (HostToCardStream<Device>, Rc<RefCell<Vec<usize>>>) {
+        let (device, writes) = device(fail, false);
+        let mut stream = HostToCardStream::new(device, 8192, 8192).unwrap();
+        stream.set_flush_policy(FlushPolicy::Manual);
+        (stream, writes)
+    }
+
+    #[test]
+    fn failed_finish_does_not_write_again() {
+        let (mut stream, writes) = stream(1);
+        stream.write_all(&[1; 5000]).unwrap();
+        assert_eq!(
+            stream.finish().unwrap_err().kind(),
+            io::ErrorKind::BrokenPipe
+        );
+        assert_eq!(*writes.borrow(), [4096]);
+    }
+
+    #[test]
+    fn failed_flush_keeps_unwritten_rest() {
+        let (mut stream, writes) = stream(1);
+        stream.write_all(&[1; 5000]).unwrap();
+        assert_eq!(
+            stream.flush().unwrap_err().kind(),
+            io::ErrorKind::BrokenPipe
+        );
+        stream.flush().unwrap();
+        assert_eq!(*writes.borrow(), [4096, 904]);
+    }
+
+    /// Threshold write-outs within a message do not wait for the file.
+    #[test]
+    fn flushes_file_at_message_end() {
+        let (mut stream, writes) = stream(usize::MAX);
+        stream.set_flush_policy(FlushPolicy::Threshold(4096));
+        stream.write_remaining(&[1; 20000]).unwrap();
+        assert_eq!(*writes.borrow(), [4, 8192, 8192, 3616]);
+        assert_eq!(stream.get_ref().unwrap().flushes, [4]);
+
+        stream.write_all(&[1; 5000]).unwrap();
+        stream.flush().unwrap();
+        assert_eq!(stream.get_ref().unwrap().flushes, [4, 6]);
+    }
+
+    #[test]
+    fn message_too_long_writes_nothing() {
+        let (mut stream, writes) = stream(usize::MAX);
+        let mut message = stream.begin_message(100).unwrap();
+        message.write_all(&[1; 60]).unwrap();
+        let err = message.write(&[2; 41]).unwrap_err();
+        assert_eq!(
+            err.get_ref().unwrap().downcast_ref(),
+            Some(&H2cError::MessageTooLong { len: 100 })
+        );
+        assert_eq!(message.remaining(), 40);
+        message.write_all(&[3; 40]).unwrap();
+        message.finish().unwrap();
+        assert_eq!(*writes.borrow(), [4, 100]);
+    }
+
+    fn h2c_error(err: io::Error) -> H2cError {
+        err.into_inner()
+            .unwrap()
+            .downcast()
+            .map(|err| *err)
+            .unwrap()
+    }
+
+    #[test]
+    fn complete_stream_short_and_long_source() {
+        let (mut stream, writes) = stream(usize::MAX);
+        let err = stream.write_complete_stream(&[1; 50][..], 100).unwrap_err();
+        assert_eq!(
+            h2c_error(err),
+            H2cError::ShortSource {
+                length: 100,
+                read: 50
+            }
+        );
+        let err = stream
+            .write_complete_stream(&[1; 150][..], 100)
+            .unwrap_err();
+        assert_eq!(h2c_error(err), H2cError::LongSource { length: 100 });
+
+        // Both messages are sent in full and the stream stays usable
+        stream.write_complete_stream(&[1; 100][..], 100).unwrap();
+        assert_eq!(*writes.borrow(), [4, 100, 4, 100, 4, 100]);
+    }
+
+    #[test]
+    fn complete_stream_rejected_before_writing() {
+        let (mut stream, writes) = stream(usize::MAX);
+        let err = stream.write_complete_stream(&[][..], 0).unwrap_err();
+        assert_eq!(h2c_error(err), H2cError::EmptyMessage);
+        let length = ESCAPE_FLAG as usize * stream.geometry().packet_size();
+        let err = stream.write_complete_stream(&[][..], length).unwrap_err();
+        assert_eq!(
+            h2c_error(err),
+            H2cError::PacketCountTooLarge {
+                count: ESCAPE_FLAG as usize
+            }
+        );
+        stream.flush().unwrap();
+        assert!(writes.borrow().is_empty());
+    }
+
+    /// Source that fails after `len` bytes.
+    struct FailingSource {
+        len: usize,
+    }
+
+    impl Read for FailingSource {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.len {
+                0 => Err(io::ErrorKind::ConnectionReset.into()),
+                _ => {
+                    let count = usize::min(buf.len(), self.len);
+                    buf[..count].fill(1);
+                    self.len -= count;
+                    Ok(count)
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn complete_stream_returns_read_error() {
+        let (mut stream, writes) = stream(usize::MAX);
+        let err = stream
+            .write_complete_stream(FailingSource { len: 50 }, 100)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        // The message is filled with zeros
+        assert_eq!(*writes.borrow(), [4, 100]);
+    }
+
+    /// The failed write poisons the stream, completing the message fails with
+    /// [`H2cError::Poisoned`].
+    #[test]
+    fn complete_stream_returns_write_error() {
+        let (mut stream, _) = stream(1);
+        let err = stream
+            .write_complete_stream(FailingSource { len: 20000 }, 20000)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        assert!(stream.is_poisoned());
+    }
+
+    #[test]
+    fn flush_policy_immediate() {
+        let (mut stream, writes) = stream(usize::MAX);
+        stream.set_flush_policy(FlushPolicy::Immediate);
+        stream.write_all(&[1; 10]).unwrap();
+        assert_eq!(*writes.borrow(), [10]);
+
+        // Inside a message, only whole packets are written early
+        let mut message = stream.begin_message(5000).unwrap();
+        message.write_all(&[1; 100]).unwrap();
+        assert_eq!(*writes.borrow(), [10, 4]);
+        message.write_all(&[1; 4000]).unwrap();
+        assert_eq!(*writes.borrow(), [10, 4, 4096]);
+        message.write_all(&[1; 900]).unwrap();
+        message.finish().unwrap();
+        assert_eq!(*writes.borrow(), [10, 4, 4096, 904]);
+    }
+
+    #[test]
+    fn flush_policy_threshold() {
+        let (mut stream, writes) = stream(usize::MAX);
+        stream.set_flush_policy(FlushPolicy::Threshold(4096));
+        stream.write_all(&[1; 100]).unwrap();
+        assert!(writes.borrow().is_empty());
+        // Only whole packets are written, the rest waits for the next write out
+        stream.write_all(&[1; 4000]).unwrap();
+        assert_eq!(*writes.borrow(), [4096]);
+        stream.flush().unwrap();
+        assert_eq!(*writes.borrow(), [4096, 4]);
+    }
+
+    #[test]
+    fn flush_policy_linger() {
+        let linger = Duration::from_millis(50);
+        let (mut stream, writes) = stream(usize::MAX);
+        stream.set_flush_policy(FlushPolicy::Linger(linger));
+        stream.write_all(&[1; 10]).unwrap();
+        assert!(writes.borrow().is_empty());
+
+        // An idle stream is only flushed by flush_expired
+        thread::sleep(linger);
+        assert!(writes.borrow().is_empty());
+        stream.flush_expired().unwrap();
+        assert_eq!(*writes.borrow(), [10]);
+        stream.flush_expired().unwrap();
+        assert_eq!(*writes.borrow(), [10]);
+
+        // The first write after the deadline writes the buffer
+        stream.write_all(&[1; 10]).unwrap();
+        assert_eq!(*writes.borrow(), [10]);
+        thread::sleep(linger);
+        stream.write_all(&[1; 10]).unwrap();
+        assert_eq!(*writes.borrow(), [10, 20]);
+    }
+
+    #[test]
+    fn flush_policy_manual() {
+        let (mut stream, writes) = stream(usize::MAX);
+        stream.write_all(&[1; 5000]).unwrap();
+        assert!(writes.borrow().is_empty());
+        // A full buffer is written
+        stream.write_all(&[1; 4000]).unwrap();
+        assert_eq!(*writes.borrow(), [8192]);
+        stream.flush().unwrap();
+        assert_eq!(*writes.borrow(), [8192, 808]);
+    }
+
+    /// The last packet of a message fails only once the message seems complete.
+    #[test]
+    fn deferred_error_poisons() {
+        let (device, writes) = device(2, true);
+        let mut stream = HostToCardStream::new(device, 8192, 8192).unwrap();
+        stream.set_deferred_errors(true);
+        let err = stream.write_remaining(&[1; 5000]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(*writes.borrow(), [4, 4096, 904]);
+        assert!(stream.is_poisoned());
+
+        let err = stream.write_remaining(&[1; 10]).unwrap_err();
+        assert_eq!(
+            err.get_ref().unwrap().downcast_ref(),
+            Some(&H2cError::Poisoned { remaining: None })
+        );
+        assert!(stream.pad_message().is_err());
+        stream.reset();
+        stream.write_remaining(&[1; 10]).unwrap();
+        assert_eq!(*writes.borrow(), [4, 4096, 904, 4, 10]);
+    }
+
+    #[test]
+    fn drop_poisoned_without_data_is_quiet() {
+        let (device, _) = device(2, true);
+        let mut stream = HostToCardStream::new(device, 8192, 8192).unwrap();
+        stream.set_deferred_errors(true);
+        stream.set_drop_policy(DropPolicy::PanicInDebug);
+        assert!(stream.write_remaining(&[1; 5000]).is_err());
+        assert!(stream.is_poisoned());
+        drop(stream);
+    }
+
+    #[test]
+    fn drop_poisoned_reports_unwritten_data() {
+        let (mut stream, _) = stream(1);
+        stream.set_drop_policy(DropPolicy::PanicInDebug);
+        assert!(stream.write_remaining(&[1; 10]).is_err());
+        assert!(stream.is_poisoned());
+        let dropped = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| drop(stream)));
+        assert_eq!(dropped.is_err(), cfg!(debug_assertions));
+    }
+
+    /// Stream options under which the ways of writing a message are compared.
+    #[derive(Debug, Clone, Copy)]
+    struct Options {
+        escape_mode: EscapeMode,
+        integrity: Integrity,
+        sequence_numbers: bool,
+    }
+
+    const OPTIONS: [Options; 3] = [
+        Options {
+            escape_mode: EscapeMode::Disabled,
+            integrity: Integrity::None,
+            sequence_numbers: false,
+        },
+        Options {
+            escape_mode: EscapeMode::Escaped,
+            integrity: Integrity::None,
+            sequence_numbers: false,
+        },
+        Options {
+            escape_mode: EscapeMode::Disabled,
+            integrity: Integrity::Crc32c,
+            sequence_numbers: true,
+        },
+    ];
+
+    fn small_geometry() -> StreamGeometry {
+        StreamGeometry::new(64, 64).unwrap()
+    }
+
+    /// Packets the card receives when [`messages`] are sent with `write`.
+    fn sent_packets(
+        options: Options,
+        mut write: impl FnMut(&mut HostToCardStream<Capture>, &[u8]) -> io::Result<()>,
+    ) -> Vec<Vec<u8>> {
+        let geometry = small_geometry();
+        let mut stream =
+            HostToCardStream::with_geometry(Capture::default(), 1024, 1024, geometry).unwrap();
+        stream.set_escape_mode(options.escape_mode);
+        stream.set_integrity(options.integrity);
+        stream.set_sequence_numbers(options.sequence_numbers);
+        for message in messages(geometry.packet_size()) {
+            write(&mut stream, &message).unwrap();
+        }
+        let writes = stream.finish().unwrap().writes;
+        test_util::packets(&writes, geometry.packet_size())
+            .into_iter()
+            .map(<[u8]>::to_vec)
+            .collect()
+    }
+
+    /// Checks that `write` sends the packets [`write_remaining`](HostToCardStream::write_remaining)
+    /// does, and that they decode to the messages.
+    fn assert_like_write_remaining(
+        mut write: impl FnMut(&mut HostToCardStream<Capture>, &[u8]) -> io::Result<()>,
+    ) {
+        for options in OPTIONS {
+            let packets = sent_packets(options, &mut write);
+            let expected = sent_packets(options, |stream, message| stream.write_remaining(message));
+            assert_eq!(packets, expected, "{:?}", options);
+
+            let mut decoder = H2cDecoder::new(small_geometry());
+            decoder.set_integrity(options.integrity);
+            decoder.set_sequence_numbers(options.sequence_numbers);
+            let mut decoded = Vec::new();
+            for packet in &packets {
+                if let Some(message) = decoder.decode(packet).unwrap() {
+                    let escape = options.escape_mode == EscapeMode::Escaped;
+                    assert_eq!(message.escape, escape, "{:?}", options);
+                    decoded.push(message.data.to_vec());
+                }
+            }
+            decoder.finish().unwrap();
+            assert_eq!(decoded, messages(64), "{:?}", options);
+        }
+    }
+
+    #[test]
+    fn message_aligned_like_write_remaining() {
+        assert_like_write_remaining(|stream, message| {
+            let mut aligned = AlignedBuf::with_geometry(message.len(), small_geometry()).unwrap();
+            aligned.write_all(message).unwrap();
+            stream.write_message_aligned(&aligned)
+        });
+    }
+
+    #[test]
+    fn message_vectored_like_write_remaining() {
+        // Parts that end within and at packets, and empty ones
+        for splits in [vec![0], vec![1, 1], vec![3, 64, 65], vec![63, 128]] {
+            assert_like_write_remaining(|stream, message| {
+                let mut parts = Vec::new();
+                let mut start = 0;
+                for &split in &splits {
+                    let split = usize::min(split, message.len());
+                    parts.push(IoSlice::new(&message[start..split]));
+                    start = split;
+                }
+                parts.push(IoSlice::new(&message[start..]));
+                stream.write_message_vectored(&parts)
+            });
+        }
+    }
+
+    /// Source returning at most `max` bytes per read.
+    struct Trickle<'a> {
+        data: &'a [u8],
+        max: usize,
+    }
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let count = usize::min(usize::min(buf.len(), self.max), self.data.len());
+            buf[..count].copy_from_slice(&self.data[..count]);
+            self.data = &self.data[count..];
+            Ok(count)
+        }
+    }
+
+    #[test]
+    fn complete_stream_like_write_remaining() {
+        assert_like_write_remaining(|stream, message| {
+            stream.write_complete_stream(message, message.len())
+        });
+        // Reads that end within packets
+        for max in [1, 7, 63, 65] {
+            assert_like_write_remaining(|stream, message| {
+                let source = Trickle { data: message, max };
+                stream.write_complete_stream(source, message.len())
+            });
+        }
     }
 }
@@ -0,0 +1,293 @@
+use super::buf::Buf;
+use std::{
+    io::{self, Write},
+    mem,
+    sync::mpsc,
+    thread,
+};
+
+enum Job {
+    Write(Buf),
+    Flush,
+    /// The error of a failed job was reported, write again.
+    Resume,
+}
+
+/// Outcome of a job: the buffer of a write, to be filled again, and the result.
+type Done = (Option<Buf>, io::Result<()>);
+
+/// Writes buffers to a file on a dedicated thread, while the stream fills the next buffer.
+///
+/// Buffers are swapped instead of copied: a submitted buffer goes to the thread and a free one
+/// takes its place. A failed write is reported by the next call, the buffers submitted after it
+/// are dropped until then.
+pub struct WriterThread<F> {
+    jobs: Option<mpsc::Sender<Job>>,
+    done: mpsc::Receiver<Done>,
+    free: Vec<Buf>,
+    error: Option<io::Error>,
+    handle: Option<thread::JoinHandle<F>>,
+}
+
+impl<F> WriterThread<F>
+where
+    F: Write + Send + 'static,
+{
+    pub fn spawn(mut file: F, free: Vec<Buf>) -> io::Result<Self> {
+        let (jobs, pending) = mpsc::channel();
+        let (done, finished) = mpsc::channel();
+
+        let handle = thread::Builder::new()
+            .name("h2c-writer".to_string())
+            .spawn(move || {
+                let mut failed = false;
+                for job in pending {
+                    let (buf, result) = match job {
+                        Job::Write(mut buf) => {
+                            let result = match failed {
+                                true => Ok(()),
+                                false => buf.write_into(&mut file),
+                            };
+                            buf.clear();
+                            (Some(buf), result)
+                        }
+                        Job::Flush if failed => (None, Ok(())),
+                        Job::Flush => (None, file.flush()),
+                        Job::Resume => {
+                            failed = false;
+                            continue;
+                        }
+                    };
+                    failed |= result.is_err();
+                    if done.send((buf, result)).is_err() {
+                        break;
+                    }
+                }
+                file
+            })?;
+
+        Ok(Self {
+            jobs: Some(jobs),
+            done: finished,
+            free,
+            error: None,
+            handle: Some(handle),
+        })
+    }
+}
+
+impl<F> WriterThread<F> {
+    /// Hands the first `len` bytes of `buf` to the thread. The rest is moved to a free buffer,
+    /// which replaces `buf`.
+    pub fn submit(&mut self, buf: &mut Buf, len: usize) -> io::Result<()> {
+        while let Ok(done) = self.done.try_recv() {
+            self.complete(done);
+        }
+        self.take_error()?;
+        if len == 0 {
+            return Ok(());
+        }
+
+        let mut next = loop {
+            if let Some(next) = self.free.pop() {
+                break next;
+            }
+            let done = self.recv()?;
+            self.complete(done);
+        };
+        next.write_all(&buf.data()[len..])?;
+        buf.truncate(len);
+        let buf = mem::replace(buf, next);
+        self.send(Job::Write(buf))
+    }
+
+    /// Waits until the thread has written and flushed all submitted buffers.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.send(Job::Flush)?;
+        loop {
+            let done = self.recv()?;
+            let flushed = done.0.is_none();
+            self.complete(done);
+            if flushed {
+                break;
+            }
+        }
+        self.take_error()
+    }
+
+    /// Waits until the thread has written everything, stops it and returns the file.
+    pub fn finish(mut self) -> io::Result<F> {
+        self.flush()?;
+        self.jobs = None;
+        let handle = self.handle.take().ok_or_else(thread_exited)?;
+        handle.join().map_err(|_| thread_exited())
+    }
+
+    fn complete(&mut self, (buf, result): Done) {
+        self.free.extend(buf);
+        if let Err(err) = result {
+            self.error.get_or_insert(err);
+        }
+    }
+
+    fn take_error(&mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(err) => {
+                self.send(Job::Resume)?;
+                Err(err)
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn send(&self, job: Job) -> io::Result<()> {
+        let sent = self.jobs.as_ref().map(|jobs| jobs.send(job));
+        match sent {
+            Some(Ok(())) => Ok(()),
+            _ => Err(thread_exited()),
+        }
+    }
+
+    fn recv(&self) -> io::Result<Done> {
+        self.done.recv().map_err(|_| thread_exited())
+    }
+}
+
+impl<F> Drop for WriterThread<F> {
+    fn drop(&mut self) {
+        // Closing the channel ends the thread once it has written all submitted buffers
+        self.jobs = None;
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn thread_exited() -> io::Error {
+    io::Error::other("writer thread exited")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::message, H2cError, HostToCardStream, StreamGeometry};
+    use std::sync::{Arc, Mutex};
+
+    /// Device that records every write and fails the write with index `fail`. Writes wait while
+    /// the test holds `gate`.
+    #[derive(Default)]
+    struct Device {
+        writes: Arc<Mutex<Vec<Vec<u8>>>>,
+        gate: Arc<Mutex<()>>,
+        calls: usize,
+        fail: Option<usize>,
+    }
+
+    impl Write for Device {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let _gate = self.gate.lock().unwrap();
+            self.calls += 1;
+            if self.fail == Some(self.calls - 1) {
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            self.writes.lock().unwrap().push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn geometry() -> StreamGeometry {
+        StreamGeometry::new(64, 64).unwrap()
+    }
+
+    fn buf(data: &[u8]) -> Buf {
+        let mut buf = Buf::new(256, geometry()).unwrap();
+        buf.write_all(data).unwrap();
+        buf
+    }
+
+    type Writes = Arc<Mutex<Vec<Vec<u8>>>>;
+
+    /// A writer thread with two free buffers, the data it wrote and the gate of its device.
+    fn thread(fail: Option<usize>) -> (WriterThread<Device>, Writes, Arc<Mutex<()>>) {
+        let device = Device {
+            fail,
+            ..Device::default()
+        };
+        let writes = Arc::clone(&device.writes);
+        let gate = Arc::clone(&device.gate);
+        let free = vec![buf(&[]), buf(&[])];
+        (WriterThread::spawn(device, free).unwrap(), writes, gate)
+    }
+
+    #[test]
+    fn submit_flush_finish() {
+        let (mut thread, writes, _) = thread(None);
+        let sent = message(200);
+        let mut buf = buf(&sent);
+        // The rest after the submitted bytes stays in the buffer
+        thread.submit(&mut buf, 128).unwrap();
+        assert_eq!(buf.data(), &sent[128..]);
+        thread.flush().unwrap();
+        assert_eq!(writes.lock().unwrap().concat(), &sent[..128]);
+
+        thread.submit(&mut buf, 72).unwrap();
+        assert_eq!(buf.len(), 0);
+        let device = thread.finish().unwrap();
+        assert_eq!(device.writes.lock().unwrap().concat(), sent);
+    }
+
+    #[test]
+    fn error_reported_then_resumed() {
+        let (mut thread, writes, gate) = thread(Some(0));
+        let held = gate.lock().unwrap();
+        thread.submit(&mut buf(&[1; 64]), 64).unwrap();
+        // Submitted before the failure of the first write is known
+        thread.submit(&mut buf(&[2; 64]), 64).unwrap();
+        drop(held);
+
+        // The failed write is reported once, the buffer submitted after it is dropped
+        let err = thread.flush().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        thread.flush().unwrap();
+        assert!(writes.lock().unwrap().is_empty());
+
+        // Reporting the error resumed the thread
+        thread.submit(&mut buf(&[3; 64]), 64).unwrap();
+        thread.flush().unwrap();
+        assert_eq!(*writes.lock().unwrap(), [vec![3; 64]]);
+    }
+
+    /// The thread reports errors late, so the stream is poisoned until it is reset.
+    #[test]
+    fn stream_poisoned_until_reset() {
+        let device = Device {
+            fail: Some(1),
+            ..Device::default()
+        };
+        let writes = Arc::clone(&device.writes);
+        let mut stream =
+            HostToCardStream::with_writer_thread(device, 256, 256, geometry(), 2).unwrap();
+        let err = stream
+            .write_remaining(&message(100))
+            .and_then(|()| stream.flush())
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        assert!(stream.is_poisoned());
+        let err = stream.write_remaining(&message(10)).unwrap_err();
+        assert_eq!(
+            err.get_ref().unwrap().downcast_ref(),
+            Some(&H2cError::Poisoned { remaining: None })
+        );
+
+        stream.reset();
+        stream.write_remaining(&message(10)).unwrap();
+        stream.flush().unwrap();
+        let writes = writes.lock().unwrap();
+        assert_eq!(writes.len(), 3);
+        assert_eq!(writes[2], message(10));
+    }
+}
@@ -1,11 +1,30 @@
+mod aligned;
 mod c2h;
+mod framing;
 mod h2c;
+#[cfg(test)]
+mod test_util;
+#[cfg(feature = "io-uring")]
+pub mod uring;
 mod util;
 
 pub mod ctl;
+pub mod emulator;
 pub mod managed;
 
-pub use self::{c2h::CardToHostStream, h2c::HostToCardStream};
+pub use self::{
+    aligned::AlignedBuf,
+    c2h::{
+        C2hDecoder, C2hEncoder, C2hError, C2hEvent, CardToHostStream, LastEncoding, MessageReader,
+        ProtocolState, Resync,
+    },
+    framing::{EscapeMode, Integrity, StreamGeometry, CTRL_SEQ, ESCAPE_FLAG},
+    h2c::{
+        DropPolicy, FlushPolicy, H2cDecoder, H2cError, H2cMessage, HostToCardStream, MessageWriter,
+    },
+};
+#[cfg(feature = "tokio")]
+pub use self::{c2h::AsyncCardToHostStream, h2c::AsyncHostToCardStream};
 
 pub const PACKET_SIZE: usize = 4096;
 pub const ALIGN: usize = 4096;
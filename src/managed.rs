@@ -3,6 +3,7 @@ use anyhow::Result;
 use std::{
     fs,
     io::{Read, Write},
+    os::fd::{AsRawFd, RawFd},
 };
 
 pub struct ManagedCardToHostStreamFile {
@@ -70,6 +71,12 @@ impl Read for ManagedCardToHostStreamFile {
     }
 }
 
+impl AsRawFd for ManagedCardToHostStreamFile {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
 impl Drop for ManagedCardToHostStreamFile {
     fn drop(&mut self) {
         if let Err(err) = self.stop_impl() {
@@ -141,6 +148,12 @@ impl Write for ManagedHostToCardStreamFile {
     }
 }
 
+impl AsRawFd for ManagedHostToCardStreamFile {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
 impl Drop for ManagedHostToCardStreamFile {
     fn drop(&mut self) {
         if let Err(err) = self.stop_impl() {
@@ -0,0 +1,44 @@
+//! Fixtures shared by the unit tests.
+
+use std::io::{self, Write};
+
+/// Message lengths around and at multiples of the packet size.
+pub fn lengths(packet_size: usize) -> Vec<usize> {
+    let p = packet_size;
+    vec![1, p - 1, p, p + 1, 2 * p, 3 * p, 7 * p, 7 * p + 3]
+}
+
+/// Message of `len` bytes whose content depends on its length.
+pub fn message(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + len) as u8).collect()
+}
+
+/// Messages of all [`lengths`].
+pub fn messages(packet_size: usize) -> Vec<Vec<u8>> {
+    lengths(packet_size).into_iter().map(message).collect()
+}
+
+/// Device that records every write, like a capture of an H2C queue.
+#[derive(Debug, Default)]
+pub struct Capture {
+    pub writes: Vec<Vec<u8>>,
+}
+
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writes.push(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Packets of `writes`, which are split like the card does.
+pub fn packets(writes: &[Vec<u8>], packet_size: usize) -> Vec<&[u8]> {
+    writes
+        .iter()
+        .flat_map(|write| write.chunks(packet_size))
+        .collect()
+}
@@ -0,0 +1,189 @@
+//! io_uring transport.
+//!
+//! [`UringReader`] and [`UringWriter`] wrap a device file and keep several reads or writes of
+//! aligned chunks in flight, so that a queue needs far fewer system calls than with plain
+//! [`read`](std::io::Read::read) and [`write`](std::io::Write::write). They implement the std I/O
+//! traits and are used as the file of a [`CardToHostStream`](crate::CardToHostStream) or
+//! [`HostToCardStream`](crate::HostToCardStream), which keep doing the framing.
+//!
+//! The operations of a batch are linked, so the kernel executes them in order and the file
+//! position is used like with plain reads and writes. This works with the QDMA devices as well as
+//! with regular files and pipes.
+
+mod reader;
+mod writer;
+
+pub use self::{reader::UringReader, writer::UringWriter};
+
+use crate::util::{mem_aligned, mem_aligned_free};
+use anyhow::{ensure, Result};
+use io_uring::{opcode, squeue, IoUring};
+use std::{io, ptr::NonNull};
+
+/// Chunk size used by the `new` constructors.
+const DEFAULT_CHUNK_SIZE: usize = 256 * 1024;
+/// Number of chunks used by the `new` constructors.
+const DEFAULT_DEPTH: usize = 8;
+
+/// User data of cancel operations, which have no chunk.
+const CANCEL: u64 = u64::MAX;
+
+unsafe impl Send for Chunk {}
+
+/// Aligned buffer an operation reads into or writes from.
+struct Chunk {
+    ptr: NonNull<u8>,
+    size: usize,
+    align: usize,
+    /// Bytes to write, or the result of the last read.
+    len: usize,
+    /// Bytes already written or returned.
+    pos: usize,
+}
+
+impl Chunk {
+    fn new(size: usize, align: usize) -> Result<Self> {
+        Ok(Self {
+            ptr: mem_aligned(size, align)?,
+            size,
+            align,
+            len: 0,
+            pos: 0,
+        })
+    }
+
+    /// Returns the bytes not yet written or returned.
+    fn data(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr().add(self.pos), self.len - self.pos) }
+    }
+}
+
+impl Drop for Chunk {
+    fn drop(&mut self) {
+        unsafe {
+            mem_aligned_free(self.ptr.as_ptr(), self.size, self.align);
+        }
+    }
+}
+
+/// Ring and chunks of one file, with at most one chain of operations in flight.
+struct Queue {
+    ring: IoUring,
+    chunks: Vec<Chunk>,
+    /// Result of the last operation on each chunk, `None` while it is in flight.
+    results: Vec<Option<i32>>,
+    /// Operations whose completion has not been reaped yet.
+    in_flight: usize,
+}
+
+impl Queue {
+    fn new(chunk_size: usize, depth: usize, align: usize) -> Result<Self> {
+        ensure!(depth > 0, "depth is zero");
+        ensure!(u32::try_from(chunk_size).is_ok(), "chunk size too large");
+
+        // Leave room for a cancel operation per chunk
+        let entries = u32::try_from(2 * depth)?.next_power_of_two();
+        let chunks = (0..depth)
+            .map(|_| Chunk::new(chunk_size, align))
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            ring: IoUring::new(entries)?,
+            chunks,
+            results: vec![Some(0); depth],
+            in_flight: 0,
+        })
+    }
+
+    fn chunk_size(&self) -> usize {
+        self.chunks[0].size
+    }
+
+    /// Submits one operation per chunk as a chain that the kernel executes in order. `op` builds
+    /// the operation for a chunk. With `link`, [`IO_LINK`](squeue::Flags::IO_LINK) or
+    /// [`IO_HARDLINK`](squeue::Flags::IO_HARDLINK), decides whether a short transfer cancels the
+    /// rest of the chain.
+    fn submit_chain(
+        &mut self,
+        chunks: impl ExactSizeIterator<Item = usize>,
+        link: squeue::Flags,
+        op: impl Fn(&mut Chunk) -> squeue::Entry,
+    ) -> io::Result<()> {
+        debug_assert_eq!(self.in_flight, 0);
+
+        let len = chunks.len();
+        for (i, chunk) in chunks.enumerate() {
+            let flags = if i + 1 < len {
+                link
+            } else {
+                squeue::Flags::empty()
+            };
+            let entry = op(&mut self.chunks[chunk])
+                .flags(flags)
+                .user_data(chunk as u64);
+            // The ring has room for a chain over all chunks
+            unsafe { self.ring.submission().push(&entry).unwrap() };
+            self.results[chunk] = None;
+            self.in_flight += 1;
+        }
+
+        self.ring.submit()?;
+        Ok(())
+    }
+
+    /// Collects completions, waiting for at least one if `wait` is set and an operation is in
+    /// flight.
+    fn reap(&mut self, wait: bool) -> io::Result<()> {
+        if wait && self.in_flight > 0 {
+            loop {
+                match self.ring.submit_and_wait(1) {
+                    Ok(_) => break,
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+
+        for entry in self.ring.completion() {
+            if entry.user_data() != CANCEL {
+                self.results[entry.user_data() as usize] = Some(entry.result());
+                self.in_flight -= 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Cancels the operations in flight.
+    fn cancel(&mut self) -> io::Result<()> {
+        for chunk in 0..self.chunks.len() {
+            if self.results[chunk].is_none() {
+                let entry = opcode::AsyncCancel::new(chunk as u64)
+                    .build()
+                    .user_data(CANCEL);
+                unsafe { self.ring.submission().push(&entry).unwrap() };
+            }
+        }
+        self.ring.submit()?;
+        Ok(())
+    }
+
+    /// Waits until no operation is in flight anymore. If that fails, the chunks are leaked
+    /// instead of being freed while the kernel might still access them.
+    fn wait_idle(&mut self) {
+        while self.in_flight > 0 {
+            if self.reap(true).is_err() {
+                std::mem::forget(std::mem::take(&mut self.chunks));
+                return;
+            }
+        }
+    }
+}
+
+/// Converts the result of an operation to the number of bytes transferred.
+fn result_len(result: i32) -> io::Result<usize> {
+    if result < 0 {
+        Err(io::Error::from_raw_os_error(-result))
+    } else {
+        Ok(result as usize)
+    }
+}
@@ -0,0 +1,251 @@
+use super::{result_len, Queue, DEFAULT_CHUNK_SIZE, DEFAULT_DEPTH};
+use crate::StreamGeometry;
+use anyhow::{ensure, Result};
+use io_uring::{opcode, squeue, types};
+use std::{
+    collections::VecDeque,
+    io::{self, Read},
+    os::fd::AsRawFd,
+};
+
+/// Reads a file through io_uring, with reads of all free chunks in flight.
+///
+/// Reads are issued in chains over the free chunks, a new chain once the previous one has
+/// completed. [`read`](Read::read) copies from the completed chunks in stream order.
+pub struct UringReader<F>
+where
+    F: AsRawFd,
+{
+    queue: Queue,
+    /// Chunks with a read submitted, in stream order.
+    order: VecDeque<usize>,
+    /// Chunks available for the next chain.
+    free: Vec<usize>,
+    file: F,
+}
+
+impl<F> UringReader<F>
+where
+    F: AsRawFd,
+{
+    pub fn new(file: F) -> Result<Self> {
+        Self::with_geometry(
+            file,
+            DEFAULT_CHUNK_SIZE,
+            DEFAULT_DEPTH,
+            StreamGeometry::default(),
+        )
+    }
+
+    /// Creates a reader with `depth` chunks of `chunk_size` bytes, aligned for the queue.
+    /// `chunk_size` must be a multiple of the alignment.
+    pub fn with_geometry(
+        file: F,
+        chunk_size: usize,
+        depth: usize,
+        geometry: StreamGeometry,
+    ) -> Result<Self> {
+        ensure!(
+            chunk_size > 0 && chunk_size.is_multiple_of(geometry.align()),
+            "chunk size must be a multiple of the alignment"
+        );
+
+        Ok(Self {
+            queue: Queue::new(chunk_size, depth, geometry.align())?,
+            order: VecDeque::with_capacity(depth),
+            free: (0..depth).collect(),
+            file,
+        })
+    }
+
+    pub fn get_ref(&self) -> &F {
+        &self.file
+    }
+
+    /// Submits reads of the free chunks, unless a chain is still in flight.
+    fn submit(&mut self) -> io::Result<()> {
+        if self.queue.in_flight > 0 || self.free.is_empty() {
+            return Ok(());
+        }
+
+        let fd = types::Fd(self.file.as_raw_fd());
+        let len = self.queue.chunk_size() as u32;
+        // A short read must not cancel the rest of the chain
+        let link = squeue::Flags::IO_HARDLINK;
+        self.queue
+            .submit_chain(self.free.iter().copied(), link, |chunk| {
+                chunk.len = 0;
+                chunk.pos = 0;
+                // Read at the file position, like read(2)
+                opcode::Read::new(fd, chunk.ptr.as_ptr(), len)
+                    .offset(u64::MAX)
+                    .build()
+            })?;
+        self.order.extend(self.free.drain(..));
+
+        Ok(())
+    }
+
+    fn pop_front(&mut self) {
+        let chunk = self.order.pop_front().unwrap();
+        self.free.push(chunk);
+    }
+}
+
+impl<F> Read for UringReader<F>
+where
+    F: AsRawFd,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut count = 0;
+        while count < buf.len() {
+            self.queue.reap(false)?;
+            self.submit()?;
+
+            let chunk = *self.order.front().unwrap();
+            let result = match self.queue.results[chunk] {
+                Some(result) => result,
+                None if count > 0 => break,
+                None => {
+                    self.queue.reap(true)?;
+                    continue;
+                }
+            };
+
+            let len = match result_len(result) {
+                Ok(0) => {
+                    self.pop_front();
+                    break;
+                }
+                Ok(len) => len,
+                Err(_) if count > 0 => break,
+                Err(err) => {
+                    self.pop_front();
+                    return Err(err);
+                }
+            };
+
+            let chunk = &mut self.queue.chunks[chunk];
+            chunk.len = len;
+            let data = chunk.data();
+            let n = usize::min(data.len(), buf.len() - count);
+            buf[count..count + n].copy_from_slice(&data[..n]);
+            count += n;
+            chunk.pos += n;
+            if chunk.pos == len {
+                self.pop_front();
+            }
+        }
+
+        Ok(count)
+    }
+}
+
+impl<F> Drop for UringReader<F>
+where
+    F: AsRawFd,
+{
+    fn drop(&mut self) {
+        // Reads may wait for data forever
+        if self.queue.cancel().is_ok() {
+            self.queue.wait_idle();
+        } else {
+            std::mem::forget(std::mem::take(&mut self.queue.chunks));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_util::{message, messages},
+        C2hEncoder, CardToHostStream,
+    };
+    use std::{
+        io::{Seek, Write},
+        os::unix::net::UnixStream,
+        thread,
+    };
+
+    fn geometry() -> StreamGeometry {
+        StreamGeometry::new(64, 64).unwrap()
+    }
+
+    /// Reader with chunks of 128 bytes, so that the data spans several chains.
+    fn reader<F: AsRawFd>(file: F) -> UringReader<F> {
+        UringReader::with_geometry(file, 128, 4, geometry()).unwrap()
+    }
+
+    /// End of file is reported at the end of the data and by every read after it.
+    fn assert_eof<F: AsRawFd>(reader: &mut UringReader<F>) {
+        for _ in 0..8 {
+            assert_eq!(reader.read(&mut [0; 16]).unwrap(), 0);
+        }
+    }
+
+    #[test]
+    fn round_trip_socket() {
+        let (mut tx, rx) = UnixStream::pair().unwrap();
+        let sent = message(5000);
+        let writer = thread::spawn({
+            let sent = sent.clone();
+            move || {
+                for part in sent.chunks(300) {
+                    tx.write_all(part).unwrap();
+                }
+            }
+        });
+
+        let mut reader = reader(rx);
+        let mut received = Vec::new();
+        reader.read_to_end(&mut received).unwrap();
+        writer.join().unwrap();
+        assert_eq!(received, sent);
+        assert_eof(&mut reader);
+    }
+
+    #[test]
+    fn round_trip_file() {
+        for len in [0, 1, 127, 128, 129, 512, 5000] {
+            let sent = message(len);
+            let mut file = tempfile::tempfile().unwrap();
+            file.write_all(&sent).unwrap();
+            file.rewind().unwrap();
+
+            let mut reader = reader(file);
+            let mut received = Vec::new();
+            // Reads of odd sizes, which end within chunks
+            loop {
+                let mut buf = [0; 100];
+                match reader.read(&mut buf).unwrap() {
+                    0 => break,
+                    count => received.extend_from_slice(&buf[..count]),
+                }
+            }
+            assert_eq!(received, sent, "file of {} bytes", len);
+            assert_eof(&mut reader);
+        }
+    }
+
+    #[test]
+    fn c2h_stream_from_encoder() {
+        let encoder = C2hEncoder::new(geometry());
+        let mut encoded = Vec::new();
+        for sent in messages(geometry().packet_size()) {
+            encoder.encode(&sent, &mut encoded).unwrap();
+        }
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(&encoded).unwrap();
+        file.rewind().unwrap();
+
+        let mut c2h = CardToHostStream::with_geometry(reader(file), 1024, geometry()).unwrap();
+        for sent in messages(geometry().packet_size()) {
+            let mut received = Vec::new();
+            c2h.read_complete_stream(&mut received).unwrap();
+            assert_eq!(received, sent, "message of {} bytes", sent.len());
+        }
+        let err = c2h.read_complete_stream(Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
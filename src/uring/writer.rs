@@ -0,0 +1,332 @@
+use super::{result_len, Queue, DEFAULT_CHUNK_SIZE, DEFAULT_DEPTH};
+use crate::StreamGeometry;
+use anyhow::{ensure, Result};
+use io_uring::{opcode, squeue, types};
+use std::{
+    collections::VecDeque,
+    io::{self, Write},
+    os::fd::AsRawFd,
+};
+
+/// Writes a file through io_uring, with writes of several chunks in flight.
+///
+/// Every call to [`write`](Write::write) takes up to one chunk, which is written with a single
+/// operation, so the packet boundaries of the H2C protocol are kept as long as the chunk size is
+/// a multiple of the packet size. Filled chunks are written in chains, a new chain once the
+/// previous one has completed. [`flush`](Write::flush) waits until all chunks are written.
+///
+/// A failed write is reported by the next call, the writes submitted after it are dropped. As the
+/// file of a [`HostToCardStream`](crate::HostToCardStream), enable
+/// [`set_deferred_errors`](crate::HostToCardStream::set_deferred_errors), so that such an error
+/// poisons the stream.
+pub struct UringWriter<F>
+where
+    F: AsRawFd,
+{
+    queue: Queue,
+    /// Chunks of the chain in flight.
+    chain: Vec<usize>,
+    /// Filled chunks waiting for the next chain, in stream order.
+    filled: VecDeque<usize>,
+    free: Vec<usize>,
+    error: Option<io::Error>,
+    file: F,
+}
+
+impl<F> UringWriter<F>
+where
+    F: AsRawFd,
+{
+    pub fn new(file: F) -> Result<Self> {
+        Self::with_geometry(
+            file,
+            DEFAULT_CHUNK_SIZE,
+            DEFAULT_DEPTH,
+            StreamGeometry::default(),
+        )
+    }
+
+    /// Creates a writer with `depth` chunks of `chunk_size` bytes, aligned for the queue.
+    /// `chunk_size` must be a multiple of both the packet size and the alignment.
+    pub fn with_geometry(
+        file: F,
+        chunk_size: usize,
+        depth: usize,
+        geometry: StreamGeometry,
+    ) -> Result<Self> {
+        ensure!(
+            chunk_size > 0 && chunk_size.is_multiple_of(geometry.block_size()),
+            "chunk size must be a multiple of the packet size and the alignment"
+        );
+
+        Ok(Self {
+            queue: Queue::new(chunk_size, depth, geometry.align())?,
+            chain: Vec::with_capacity(depth),
+            filled: VecDeque::with_capacity(depth),
+            free: (0..depth).collect(),
+            error: None,
+            file,
+        })
+    }
+
+    pub fn get_ref(&self) -> &F {
+        &self.file
+    }
+
+    /// Retires the chain once it has completed and submits the filled chunks as the next one.
+    /// Waits for a completion first if `wait` is set.
+    fn progress(&mut self, wait: bool) -> io::Result<()> {
+        self.queue.reap(wait)?;
+        if self.queue.in_flight > 0 {
+            return Ok(());
+        }
+
+        // The data chained after a failed write is dropped
+        let failed = self.chain.iter().position(|&index| {
+            let result = self.queue.results[index].unwrap();
+            result < 0 && result != -libc::ECANCELED
+        });
+        // Write the rest of a short write and the writes cancelled after it again
+        for (i, &index) in self.chain.iter().enumerate().rev() {
+            let chunk = &mut self.queue.chunks[index];
+            let result = result_len(self.queue.results[index].unwrap());
+            match result {
+                _ if failed.is_some_and(|failed| i > failed) => self.free.push(index),
+                Ok(len) if chunk.pos + len == chunk.len => self.free.push(index),
+                Ok(len) => {
+                    chunk.pos += len;
+                    self.filled.push_front(index);
+                }
+                Err(err) if err.raw_os_error() == Some(libc::ECANCELED) => {
+                    self.filled.push_front(index)
+                }
+                Err(err) => {
+                    self.error = Some(err);
+                    self.free.push(index);
+                }
+            }
+        }
+        self.chain.clear();
+
+        if !self.filled.is_empty() {
+            let fd = types::Fd(self.file.as_raw_fd());
+            // A short write cancels the rest of the chain
+            let link = squeue::Flags::IO_LINK;
+            self.queue
+                .submit_chain(self.filled.iter().copied(), link, |chunk| {
+                    let data = chunk.data();
+                    // Write at the file position, like write(2)
+                    opcode::Write::new(fd, data.as_ptr(), data.len() as u32)
+                        .offset(u64::MAX)
+                        .build()
+                })?;
+            self.chain.extend(self.filled.drain(..));
+        }
+
+        Ok(())
+    }
+
+    fn take_error(&mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<F> Write for UringWriter<F>
+where
+    F: AsRawFd,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.take_error()?;
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        self.progress(false)?;
+        let index = loop {
+            if let Some(index) = self.free.pop() {
+                break index;
+            }
+            self.progress(true)?;
+        };
+
+        let chunk = &mut self.queue.chunks[index];
+        let count = usize::min(buf.len(), chunk.size);
+        unsafe {
+            std::ptr::copy_nonoverlapping(buf.as_ptr(), chunk.ptr.as_ptr(), count);
+        }
+        chunk.len = count;
+        chunk.pos = 0;
+        self.filled.push_back(index);
+        self.progress(false)?;
+
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.progress(false)?;
+        while !self.chain.is_empty() || !self.filled.is_empty() {
+            self.progress(true)?;
+        }
+        self.take_error()
+    }
+}
+
+impl<F> Drop for UringWriter<F>
+where
+    F: AsRawFd,
+{
+    fn drop(&mut self) {
+        let _ = self.flush();
+        self.queue.wait_idle();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_util::{message, messages},
+        H2cDecoder, HostToCardStream,
+    };
+    use std::{
+        fs::File,
+        io::{Read, Seek},
+        os::unix::net::{UnixDatagram, UnixStream},
+        thread,
+    };
+
+    const CHUNK_SIZE: usize = 128;
+
+    fn geometry() -> StreamGeometry {
+        StreamGeometry::new(64, 64).unwrap()
+    }
+
+    fn writer<F: AsRawFd>(file: F) -> UringWriter<F> {
+        UringWriter::with_geometry(file, CHUNK_SIZE, 4, geometry()).unwrap()
+    }
+
+    fn contents(mut file: &File) -> Vec<u8> {
+        let mut contents = Vec::new();
+        file.rewind().unwrap();
+        file.read_to_end(&mut contents).unwrap();
+        contents
+    }
+
+    #[test]
+    fn round_trip_socket() {
+        let (tx, mut rx) = UnixStream::pair().unwrap();
+        let sent = message(5000);
+        let reader = thread::spawn(move || {
+            let mut received = Vec::new();
+            rx.read_to_end(&mut received).unwrap();
+            received
+        });
+
+        let mut writer = writer(tx);
+        for part in sent.chunks(300) {
+            writer.write_all(part).unwrap();
+        }
+        writer.flush().unwrap();
+        drop(writer);
+        assert_eq!(reader.join().unwrap(), sent);
+    }
+
+    #[test]
+    fn round_trip_file() {
+        for len in [1, 127, 128, 129, 512, 5000] {
+            let sent = message(len);
+            let file = tempfile::tempfile().unwrap();
+            let mut writer = writer(file.try_clone().unwrap());
+            for part in sent.chunks(100) {
+                writer.write_all(part).unwrap();
+            }
+            writer.flush().unwrap();
+            assert_eq!(contents(&file), sent, "file of {} bytes", len);
+        }
+    }
+
+    /// A datagram socket keeps the write boundaries, which define the H2C packets.
+    #[test]
+    fn h2c_stream_to_decoder() {
+        let (tx, rx) = UnixDatagram::pair().unwrap();
+        let mut h2c = HostToCardStream::with_geometry(writer(tx), 512, 512, geometry()).unwrap();
+        h2c.set_deferred_errors(true);
+        for sent in messages(geometry().packet_size()) {
+            h2c.write_remaining(&sent).unwrap();
+        }
+        drop(h2c.finish().unwrap());
+
+        let mut decoder = H2cDecoder::new(geometry());
+        let mut received = Vec::new();
+        rx.set_nonblocking(true).unwrap();
+        let mut buf = [0; CHUNK_SIZE];
+        while let Ok(len) = rx.recv(&mut buf) {
+            for packet in buf[..len].chunks(geometry().packet_size()) {
+                if let Some(message) = decoder.decode(packet).unwrap() {
+                    received.push(message.data.to_vec());
+                }
+            }
+        }
+        assert_eq!(received, messages(geometry().packet_size()));
+    }
+
+    /// Fills a chunk per part of `data` as a chain that completed with `results`, without
+    /// submitting it. The data of the transfers reported by `results` is written to the file.
+    fn completed_chain(writer: &mut UringWriter<File>, data: &[u8], results: &[i32]) {
+        for (part, &result) in data.chunks(CHUNK_SIZE).zip(results) {
+            let index = writer.free.pop().unwrap();
+            let chunk = &mut writer.queue.chunks[index];
+            unsafe {
+                std::ptr::copy_nonoverlapping(part.as_ptr(), chunk.ptr.as_ptr(), part.len());
+            }
+            chunk.len = part.len();
+            chunk.pos = 0;
+            writer.chain.push(index);
+            writer.queue.results[index] = Some(result);
+            if result > 0 {
+                writer
+                    .get_ref()
+                    .write_all(&part[..result as usize])
+                    .unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn short_write_resubmits_rest_and_cancelled() {
+        let sent = message(3 * CHUNK_SIZE);
+        let file = tempfile::tempfile().unwrap();
+        let mut writer = writer(file.try_clone().unwrap());
+        let cancelled = -libc::ECANCELED;
+        completed_chain(&mut writer, &sent, &[10, cancelled, cancelled]);
+
+        writer.flush().unwrap();
+        assert_eq!(contents(&file), sent);
+        assert_eq!(writer.free.len(), 4);
+    }
+
+    #[test]
+    fn failed_write_drops_rest_of_chain() {
+        let sent = message(3 * CHUNK_SIZE);
+        let file = tempfile::tempfile().unwrap();
+        let mut writer = writer(file.try_clone().unwrap());
+        completed_chain(
+            &mut writer,
+            &sent,
+            &[CHUNK_SIZE as i32, -libc::EIO, -libc::ECANCELED],
+        );
+
+        let err = writer.flush().unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EIO));
+        assert_eq!(contents(&file), &sent[..CHUNK_SIZE]);
+        assert_eq!(writer.free.len(), 4);
+
+        // The writer continues after the error
+        writer.write_all(&sent[..10]).unwrap();
+        writer.flush().unwrap();
+        assert_eq!(contents(&file).len(), CHUNK_SIZE + 10);
+    }
+}